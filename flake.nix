@@ -14,6 +14,8 @@
           name = "datacenter-api";
           src = craneLib.cleanCargoSource ./.;
           checkInputs = [ pkgs.nix ];
+          cargoTestExtraArgs = "--features nix-tests";
+          GIT_COMMIT = inputs.self.rev or inputs.self.dirtyRev or "";
           preConfigure = ''
             mkdir -p web
             cp -rT ${self'.packages.web} web/dist
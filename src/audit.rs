@@ -0,0 +1,125 @@
+use crate::config;
+use crate::query::deserialize_flag;
+use crate::tls::ClientCertificate;
+
+use axum::Extension;
+use axum::body::Body;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Path, Query, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::sync::{Arc, Mutex};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// Command bodies are tiny; anything bigger than this is rejected outright.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    timestamp: String,
+    host: &'a str,
+    action: serde_json::Value,
+    principal: Option<&'a str>,
+    status: u16,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+}
+
+/// Records power-control actions, both to the `audit` tracing target and
+/// optionally to an append-only file of JSON lines.
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(config: Option<&config::Audit>) -> anyhow::Result<AuditLog> {
+        let file = match config {
+            Some(config) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)?,
+            )),
+            None => None,
+        };
+        Ok(AuditLog { file })
+    }
+
+    /// Record a command sent to `host`. Handlers acting on several hosts call
+    /// this directly, since the `audit` middleware only knows about one.
+    pub fn record_command(
+        &self,
+        host: &str,
+        action: serde_json::Value,
+        principal: Option<&str>,
+        status: StatusCode,
+        dry_run: bool,
+    ) {
+        self.record(&AuditEntry {
+            timestamp: OffsetDateTime::now_utc().format(&Rfc3339).unwrap(),
+            host,
+            action,
+            principal,
+            status: status.as_u16(),
+            dry_run,
+        });
+    }
+
+    fn record(&self, entry: &AuditEntry) {
+        tracing::info!(
+            target: "audit",
+            host = entry.host,
+            action = %entry.action,
+            principal = entry.principal,
+            status = entry.status,
+            dry_run = entry.dry_run,
+            "power control"
+        );
+
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_vec(entry).unwrap();
+            line.push(b'\n');
+            if let Err(e) = file.lock().unwrap().write_all(&line) {
+                tracing::error!("failed to write audit log: {e}");
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AuditParams {
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    dry_run: bool,
+}
+
+/// Middleware recording the request body and outcome of every command sent to a host.
+pub async fn audit(
+    State(log): State<Arc<AuditLog>>,
+    Path(hostname): Path<String>,
+    certificate: Option<Extension<Option<ClientCertificate>>>,
+    params: Result<Query<AuditParams>, QueryRejection>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_BODY_SIZE).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let action = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    let principal = certificate
+        .as_ref()
+        .and_then(|Extension(c)| c.as_ref())
+        .and_then(|c| c.common_name.as_deref());
+    let dry_run = params.is_ok_and(|Query(p)| p.dry_run);
+    log.record_command(&hostname, action, principal, response.status(), dry_run);
+
+    response
+}
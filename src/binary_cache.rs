@@ -1,11 +1,21 @@
+use crate::blocking;
+
 use anyhow::anyhow;
-use async_compression::tokio::bufread::{XzDecoder, ZstdDecoder};
-use futures::TryStreamExt as _;
+use async_compression::tokio::bufread::{GzipDecoder, Lz4Decoder, XzDecoder, ZstdDecoder};
+use async_compression::zstd::DParameter;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use futures::{StreamExt as _, TryStreamExt as _};
+use http::StatusCode;
 use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
 use std::collections::HashMap;
+use std::fmt;
 use std::pin::Pin;
-use tokio::io::AsyncRead;
-use tokio_util::io::StreamReader;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Poll, ready};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt as _};
+use tokio_util::io::{ReaderStream, StreamReader};
 use url::Url;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -16,6 +26,8 @@ pub struct NarInfo {
     pub nar_size: u64,
     pub file_size: u64,
     pub url: String,
+    /// The SHA-256 of the uncompressed NAR, as written in the narinfo.
+    pub nar_hash: Option<String>,
 }
 
 impl NarInfo {
@@ -42,85 +54,629 @@ impl NarInfo {
                 .get("Compression")
                 .ok_or_else(|| anyhow!("Missing Compression field"))?
                 .to_string(),
+            nar_hash: fields.get("NarHash").map(|h| h.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Xz,
+    Zstd,
+    Gzip,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = BinaryCacheError;
+
+    fn from_str(s: &str) -> Result<Compression, BinaryCacheError> {
+        match s {
+            "none" => Ok(Compression::None),
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            "gzip" => Ok(Compression::Gzip),
+            "lz4" => Ok(Compression::Lz4),
+            "bzip2" => Err(BinaryCacheError::Decode(format!(
+                "Compression method {s} is not implemented yet"
+            ))),
+            _ => Err(BinaryCacheError::Decode(format!(
+                "Unsupported compression type: {s}"
+            ))),
+        }
+    }
+}
+
+/// Largest zstd window accepted, as a power of two. The decoder's default of
+/// 2^27 rejects NARs compressed with `--long=28` and above; 2^31 is as far as
+/// zstd goes. The window a frame asks for is allocated for the length of the
+/// download, so with long-mode NARs each concurrent download can take up to
+/// 2 GiB of memory, which `pxe.max_concurrent_downloads` bounds.
+const ZSTD_WINDOW_LOG_MAX: u32 = 31;
+
+impl Compression {
+    pub fn decode<'a>(
+        self,
+        r: impl AsyncBufRead + Send + 'a,
+    ) -> Pin<Box<dyn AsyncRead + Send + 'a>> {
+        match self {
+            Compression::None => Box::pin(r),
+            Compression::Xz => Box::pin(XzDecoder::new(r)),
+            Compression::Zstd => Box::pin(ZstdDecoder::with_params(
+                r,
+                &[DParameter::window_log_max(ZSTD_WINDOW_LOG_MAX)],
+            )),
+            Compression::Gzip => Box::pin(GzipDecoder::new(r)),
+            Compression::Lz4 => Box::pin(Lz4Decoder::new(r)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BinaryCacheError {
+    NotFound,
+    Http(StatusCode),
+    Decode(String),
+    SizeMismatch { expected: u64, actual: u64 },
+    TooLarge { size: u64, limit: u64 },
+    HashMismatch { expected: String, actual: String },
+    Io(std::io::Error),
+}
+
+impl fmt::Display for BinaryCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryCacheError::NotFound => write!(f, "path not found in binary cache"),
+            BinaryCacheError::Http(status) => write!(f, "binary cache returned {status}"),
+            BinaryCacheError::Decode(msg) => {
+                write!(f, "cannot decode binary cache response: {msg}")
+            }
+            BinaryCacheError::SizeMismatch { expected, actual } => {
+                write!(f, "NAR ended after {actual} bytes, expected {expected}")
+            }
+            BinaryCacheError::TooLarge { size, limit } => {
+                write!(f, "NAR size {size} exceeds the maximum of {limit} bytes")
+            }
+            BinaryCacheError::HashMismatch { expected, actual } => {
+                write!(f, "NAR hash mismatch: expected {expected}, got {actual}")
+            }
+            BinaryCacheError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for BinaryCacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BinaryCacheError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BinaryCacheError {
+    fn from(e: std::io::Error) -> Self {
+        BinaryCacheError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for BinaryCacheError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(StatusCode::NOT_FOUND) => BinaryCacheError::NotFound,
+            Some(status) => BinaryCacheError::Http(status),
+            None if e.is_decode() => BinaryCacheError::Decode(e.to_string()),
+            None => BinaryCacheError::Io(std::io::Error::other(e)),
+        }
+    }
+}
+
+impl From<url::ParseError> for BinaryCacheError {
+    fn from(e: url::ParseError) -> Self {
+        BinaryCacheError::Decode(format!("invalid URL: {e}"))
+    }
+}
+
+/// Callback invoked with the number of bytes decoded so far and the expected total.
+pub type Progress = Arc<dyn Fn(u64, u64) + Send + Sync>;
+
+struct ProgressReader<R> {
+    inner: R,
+    position: u64,
+    total: u64,
+    progress: Progress,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+
+        let inner = Pin::new(&mut self.inner);
+        ready!(inner.poll_read(cx, buf))?;
+
+        let after = buf.filled().len();
+        self.position += (after - before) as u64;
+        (self.progress)(self.position, self.total);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct LimitedReader<R> {
+    inner: R,
+    position: u64,
+    limit: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+
+        let inner = Pin::new(&mut self.inner);
+        ready!(inner.poll_read(cx, buf))?;
+
+        let after = buf.filled().len();
+        self.position += (after - before) as u64;
+        if self.position > self.limit {
+            return Poll::Ready(Err(std::io::Error::other(BinaryCacheError::TooLarge {
+                size: self.position,
+                limit: self.limit,
+            })));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Yields exactly `expected` bytes, failing if the underlying reader ends
+/// early and ignoring anything past that.
+struct ExactReader<R> {
+    inner: tokio::io::Take<R>,
+    position: u64,
+    expected: u64,
+}
+
+impl<R: AsyncRead + Unpin> ExactReader<R> {
+    fn new(inner: R, expected: u64) -> ExactReader<R> {
+        ExactReader {
+            inner: inner.take(expected),
+            position: 0,
+            expected,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ExactReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+
+        let inner = Pin::new(&mut self.inner);
+        ready!(inner.poll_read(cx, buf))?;
+
+        let after = buf.filled().len();
+        self.position += (after - before) as u64;
+        if after == before && buf.remaining() > 0 && self.position < self.expected {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                BinaryCacheError::SizeMismatch {
+                    expected: self.expected,
+                    actual: self.position,
+                },
+            )));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Nix's base-32 alphabet, as used in store paths and narinfo hashes.
+const NIX32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Encode `bytes` the way Nix does, starting from the last bit.
+fn nix32(bytes: &[u8]) -> String {
+    (0..(bytes.len() * 8).div_ceil(5))
+        .rev()
+        .map(|n| {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let c = u16::from(bytes[i] >> j)
+                | bytes
+                    .get(i + 1)
+                    .map_or(0, |&next| u16::from(next) << (8 - j));
+            char::from(NIX32[usize::from(c & 0x1f)])
         })
+        .collect()
+}
+
+/// How a narinfo writes its NAR hash. Nix writes `sha256:` followed by
+/// base-32, but hexadecimal and SRI hashes are accepted too.
+#[derive(Debug, Clone, Copy)]
+enum HashEncoding {
+    Nix32,
+    Hex,
+    Sri,
+}
+
+impl HashEncoding {
+    fn of(hash: &str) -> Option<HashEncoding> {
+        if hash.starts_with("sha256-") {
+            return Some(HashEncoding::Sri);
+        }
+        match hash.strip_prefix("sha256:")?.len() {
+            52 => Some(HashEncoding::Nix32),
+            64 => Some(HashEncoding::Hex),
+            _ => None,
+        }
+    }
+
+    fn encode(self, digest: &[u8]) -> String {
+        match self {
+            HashEncoding::Nix32 => format!("sha256:{}", nix32(digest)),
+            HashEncoding::Hex => format!(
+                "sha256:{}",
+                digest
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>()
+            ),
+            HashEncoding::Sri => format!("sha256-{}", STANDARD.encode(digest)),
+        }
+    }
+}
+
+/// Hashes everything read through it, and fails at the end if the hash isn't
+/// `expected`.
+struct HashingReader<R> {
+    inner: R,
+    /// Taken once the end is reached and the hash has been checked.
+    hasher: Option<Sha256>,
+    encoding: HashEncoding,
+    expected: String,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+
+        let inner = Pin::new(&mut self.inner);
+        ready!(inner.poll_read(cx, buf))?;
+
+        let after = buf.filled().len();
+        let this = &mut *self;
+        if after > before {
+            if let Some(hasher) = &mut this.hasher {
+                hasher.update(&buf.filled()[before..]);
+            }
+        } else if buf.remaining() > 0
+            && let Some(hasher) = this.hasher.take()
+        {
+            let actual = this.encoding.encode(&hasher.finalize());
+            if actual != this.expected {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    BinaryCacheError::HashMismatch {
+                        expected: this.expected.clone(),
+                        actual,
+                    },
+                )));
+            }
+        }
+
+        Poll::Ready(Ok(()))
     }
 }
 
+/// Decompress `r` on the blocking thread pool. Decoding xz in particular is
+/// CPU-bound, and doing it on the runtime's workers while a rack boots would
+/// starve every other request.
+fn decode_in_background(
+    compression: Compression,
+    r: impl AsyncBufRead + Send + 'static,
+) -> impl AsyncRead + Send + 'static {
+    let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(blocking::run(async move {
+        let mut chunks = ReaderStream::new(compression.decode(r));
+        while let Some(chunk) = chunks.next().await {
+            // The reader was dropped, nobody wants the rest.
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    }));
+    StreamReader::new(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+#[derive(Clone)]
 pub struct BinaryCache {
     url: Url,
+    max_nar_bytes: Option<u64>,
+    narinfo_accept: String,
 }
 
 impl BinaryCache {
-    pub fn new(url: Url) -> BinaryCache {
-        BinaryCache { url }
+    pub fn new(url: Url, max_nar_bytes: Option<u64>, narinfo_accept: String) -> BinaryCache {
+        BinaryCache {
+            url,
+            max_nar_bytes,
+            narinfo_accept,
+        }
     }
 
     pub async fn fetch_narinfo(
         &self,
         client: &reqwest::Client,
         hash: &str,
-    ) -> anyhow::Result<NarInfo> {
-        let r = client
-            .get(self.url.join(&format!("{hash}.narinfo"))?)
-            .header("accept", "text/x-nix-narinfo")
+    ) -> Result<NarInfo, BinaryCacheError> {
+        let url = self.url.join(&format!("{hash}.narinfo"))?;
+        let mut r = client
+            .get(url.clone())
+            .header("accept", &self.narinfo_accept)
             .send()
             .await?;
+        // Some caches only serve narinfo under a content type of their own.
+        if r.status() == StatusCode::NOT_ACCEPTABLE {
+            r = client.get(url).header("accept", "*/*").send().await?;
+        }
         r.error_for_status_ref()?;
 
-        Ok(NarInfo::parse(&r.text().await?)?)
+        NarInfo::parse(&r.text().await?).map_err(|e| BinaryCacheError::Decode(e.to_string()))
     }
 
     pub async fn fetch_nar(
         &self,
         client: &reqwest::Client,
         narinfo: &NarInfo,
-    ) -> anyhow::Result<impl AsyncRead + Send + use<>> {
+        progress: Option<Progress>,
+    ) -> Result<impl AsyncRead + Send + use<>, BinaryCacheError> {
+        if let Some(limit) = self.max_nar_bytes
+            && narinfo.nar_size > limit
+        {
+            return Err(BinaryCacheError::TooLarge {
+                size: narinfo.nar_size,
+                limit,
+            });
+        }
+
+        let nar_hash = match &narinfo.nar_hash {
+            Some(hash) => match HashEncoding::of(hash) {
+                Some(encoding) => Some((encoding, hash.clone())),
+                None => {
+                    return Err(BinaryCacheError::Decode(format!(
+                        "unsupported NarHash {hash}"
+                    )));
+                }
+            },
+            None => None,
+        };
+
+        tracing::info!(
+            cache = %self.url,
+            nar = narinfo.url,
+            compression = narinfo.compression,
+            file_size = narinfo.file_size,
+            nar_size = narinfo.nar_size,
+            "downloading NAR"
+        );
         let r = client.get(self.url.join(&narinfo.url)?).send().await?;
         r.error_for_status_ref()?;
 
         let stream = r.bytes_stream().map_err(std::io::Error::other);
         let reader = StreamReader::new(stream);
 
-        match narinfo.compression.as_str() {
-            "none" => Ok(Box::pin(reader) as Pin<Box<dyn AsyncRead + Send>>),
-            "xz" => Ok(Box::pin(XzDecoder::new(reader))),
-            "zstd" => Ok(Box::pin(ZstdDecoder::new(reader))),
-            "bzip2" | "gzip" => anyhow::bail!(
-                "Compression method {} is not implemented yet",
-                narinfo.compression
-            ),
-            _ => {
-                anyhow::bail!("Unsupported compression type: {}", narinfo.compression);
-            }
+        let mut decoded: Pin<Box<dyn AsyncRead + Send>> = match narinfo.compression.parse()? {
+            Compression::None => Box::pin(ExactReader::new(reader, narinfo.nar_size)),
+            compression => Box::pin(decode_in_background(compression, reader)),
+        };
+
+        if let Some((encoding, expected)) = nar_hash {
+            decoded = Box::pin(HashingReader {
+                inner: decoded,
+                hasher: Some(Sha256::new()),
+                encoding,
+                expected,
+            });
         }
-    }
 
-    pub async fn download(
-        &self,
-        client: &reqwest::Client,
-        hash: &str,
-    ) -> anyhow::Result<impl AsyncRead + Send + use<>> {
-        println!("Downloading {hash} from {}", self.url);
+        if let Some(limit) = self.max_nar_bytes {
+            decoded = Box::pin(LimitedReader {
+                inner: decoded,
+                position: 0,
+                limit,
+            });
+        }
 
-        let narinfo = self.fetch_narinfo(client, hash).await?;
-        let result = self.fetch_nar(client, &narinfo).await?;
-        Ok(result)
+        match progress {
+            Some(progress) => Ok(Box::pin(ProgressReader {
+                inner: decoded,
+                position: 0,
+                total: narinfo.nar_size,
+                progress,
+            }) as Pin<Box<dyn AsyncRead + Send>>),
+            None => Ok(decoded),
+        }
     }
 }
 
+/// Where `narinfo`'s compression falls in `preference`, with anything not
+/// listed coming last.
+fn preference_rank(preference: &[Compression], narinfo: &NarInfo) -> usize {
+    narinfo
+        .compression
+        .parse()
+        .ok()
+        .and_then(|c| preference.iter().position(|p| *p == c))
+        .unwrap_or(preference.len())
+}
+
+/// Download `hash` from the first cache that has it. If a compression
+/// `preference` is given, every cache is asked and the NAR is instead taken
+/// from whichever uses the most preferred compression, falling back to cache
+/// order among equals.
 pub async fn download(
     client: &reqwest::Client,
     caches: &[BinaryCache],
     hash: &str,
-) -> anyhow::Result<impl AsyncRead + Send + use<>> {
-    let mut error = anyhow::anyhow!("No configured binary cache");
+    preference: &[Compression],
+    progress: Option<Progress>,
+) -> Result<impl AsyncRead + Send + use<>, BinaryCacheError> {
+    let mut error = BinaryCacheError::NotFound;
+    // Don't let a later miss hide a more interesting failure.
+    let mut record = |err| {
+        if !matches!(err, BinaryCacheError::NotFound) {
+            error = err;
+        }
+    };
+
+    let mut candidates = Vec::new();
     for c in caches {
-        match c.download(client, hash).await {
-            Ok(result) => return Ok(result),
-            Err(err) => {
-                error = err;
+        match c.fetch_narinfo(client, hash).await {
+            Ok(narinfo) if preference.is_empty() => {
+                match c.fetch_nar(client, &narinfo, progress.clone()).await {
+                    Ok(result) => return Ok(result),
+                    Err(err) => record(err),
+                }
             }
+            Ok(narinfo) => candidates.push((c, narinfo)),
+            Err(err) => record(err),
+        }
+    }
+
+    // The sort is stable, so equally preferred caches stay in order.
+    candidates.sort_by_key(|(_, narinfo)| preference_rank(preference, narinfo));
+    for (c, narinfo) in candidates {
+        match c.fetch_nar(client, &narinfo, progress.clone()).await {
+            Ok(result) => return Ok(result),
+            Err(err) => record(err),
         }
     }
     Err(error)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BinaryCache, BinaryCacheError, Compression, NarInfo, nix32};
+    use http::{HeaderMap, StatusCode};
+    use sha2::{Digest as _, Sha256};
+    use tokio::io::AsyncReadExt as _;
+    use url::Url;
+
+    const HASH: &str = "0c0v2bv5yb4wxr9l2kzb0dwbjmgqdxv8";
+
+    #[tokio::test]
+    async fn narinfo_retries_not_acceptable() {
+        // Like some caches, only accept our own content type or anything.
+        let app = axum::Router::new().fallback(|headers: HeaderMap| async move {
+            match headers.get("accept").and_then(|v| v.to_str().ok()) {
+                Some("application/x-nix-narinfo" | "*/*") => (
+                    StatusCode::OK,
+                    "URL: nar/x.nar\nCompression: none\nNarSize: 1\nFileSize: 1\n",
+                ),
+                _ => (StatusCode::NOT_ACCEPTABLE, ""),
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        for accept in ["text/x-nix-narinfo", "application/x-nix-narinfo"] {
+            let cache = BinaryCache::new(url.clone(), None, accept.to_owned());
+            let narinfo = cache.fetch_narinfo(&client, HASH).await.unwrap();
+            assert_eq!(narinfo.url, "nar/x.nar");
+        }
+    }
+
+    #[tokio::test]
+    async fn zstd_long_window() {
+        use async_compression::Level;
+        use async_compression::tokio::bufread::ZstdEncoder;
+        use async_compression::zstd::CParameter;
+
+        let data: Vec<u8> = (0..1 << 16).map(|i| (i * 7 % 251) as u8).collect();
+        let mut compressed = Vec::new();
+        ZstdEncoder::with_quality_and_params(
+            &data[..],
+            Level::Fastest,
+            &[
+                CParameter::enable_long_distance_matching(true),
+                CParameter::window_log(28),
+            ],
+        )
+        .read_to_end(&mut compressed)
+        .await
+        .unwrap();
+
+        let mut decoded = Vec::new();
+        Compression::Zstd
+            .decode(&compressed[..])
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn nix32_matches_nix() {
+        assert_eq!(
+            nix32(&Sha256::digest(b"")),
+            "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73"
+        );
+    }
+
+    #[tokio::test]
+    async fn nar_hash_is_checked() {
+        let app = axum::Router::new().fallback(|| async { "not really a NAR" });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let cache = BinaryCache::new(url, None, "text/x-nix-narinfo".to_owned());
+        let digest = Sha256::digest(b"not really a NAR");
+        let fetch = |nar_hash: String| {
+            let narinfo = NarInfo {
+                compression: "none".to_owned(),
+                nar_size: 16,
+                file_size: 16,
+                url: "nar/x.nar".to_owned(),
+                nar_hash: Some(nar_hash),
+            };
+            let (client, cache) = (&client, &cache);
+            async move {
+                let mut data = Vec::new();
+                let mut nar = cache.fetch_nar(client, &narinfo, None).await.unwrap();
+                nar.read_to_end(&mut data).await.map(|_| data)
+            }
+        };
+
+        for good in [
+            format!("sha256:{}", nix32(&digest)),
+            format!("sha256:{digest:x}"),
+        ] {
+            assert_eq!(fetch(good).await.unwrap(), b"not really a NAR");
+        }
+
+        let bad = format!("sha256:{}", nix32(&Sha256::digest(b"")));
+        let err = fetch(bad).await.unwrap_err();
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref()),
+            Some(BinaryCacheError::HashMismatch { .. })
+        ));
+    }
+}
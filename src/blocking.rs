@@ -0,0 +1,65 @@
+use futures::task::ArcWake;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+
+/// Wakes `run` up to poll its future again.
+struct Woken(Notify);
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.notify_one();
+    }
+}
+
+/// Drive `future` to completion on the blocking thread pool, for CPU-heavy
+/// work such as decompressing and extracting NARs. Each poll runs on a
+/// blocking thread, so a thread is only taken while the future has work to
+/// do, and not while it waits on the network.
+pub async fn run<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let woken = Arc::new(Woken(Notify::new()));
+    let mut future = Box::pin(future);
+    loop {
+        let waker = futures::task::waker(woken.clone());
+        let (returned, poll) = tokio::task::spawn_blocking(move || {
+            let poll = future.as_mut().poll(&mut Context::from_waker(&waker));
+            (future, poll)
+        })
+        .await
+        .unwrap_or_else(|e| panic!("blocking future panicked: {e:?}"));
+        if let Poll::Ready(output) = poll {
+            return output;
+        }
+        future = returned;
+        // A wake-up which came during the poll is kept until here.
+        woken.0.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+
+    #[tokio::test]
+    async fn polls_off_the_runtime() {
+        let runtime_thread = std::thread::current().id();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let task = tokio::spawn(run(async move {
+            let first = std::thread::current().id();
+            let value = rx.await.unwrap();
+            (first, std::thread::current().id(), value)
+        }));
+
+        // Make the future wait, so it has to be woken up and polled again.
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        tx.send(42).unwrap();
+        let (first, second, value) = task.await.unwrap();
+        assert_ne!(first, runtime_thread);
+        assert_ne!(second, runtime_thread);
+        assert_eq!(value, 42);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::request_id;
+
+use axum::Json;
+use axum::response::{IntoResponse, Response};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// The body of every error the API returns, whether as a whole response or
+/// for one host among several.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiError {
+    /// The status to respond with. It isn't part of the body, and is ignored
+    /// where the error is reported inside a successful response.
+    #[serde(skip, default = "internal_server_error")]
+    pub status: StatusCode,
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ErrorKind>,
+    /// The `x-request-id` of the request which failed, to find it in the logs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// What went wrong, for clients which handle some failures differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// No session could be established with the BMC.
+    Unreachable,
+    /// A command failed on an established session with the BMC.
+    CommandError,
+}
+
+fn internal_server_error() -> StatusCode {
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>) -> ApiError {
+        ApiError {
+            status,
+            error: error.into(),
+            kind: None,
+            request_id: request_id::current(),
+        }
+    }
+
+    pub fn with_kind(self, kind: ErrorKind) -> ApiError {
+        ApiError {
+            kind: Some(kind),
+            ..self
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiError;
+    use axum::response::IntoResponse;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn status_is_not_in_the_body() {
+        let response = ApiError::new(StatusCode::LOCKED, "in maintenance").into_response();
+        assert_eq!(response.status(), StatusCode::LOCKED);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "error": "in maintenance" }));
+    }
+}
@@ -1,31 +1,113 @@
-use crate::config::Config;
-use crate::ipmi::{ChassisControl, GetChassisStatus, PowerRestorePolicy, ipmi_do};
+use crate::audit::{self, AuditLog};
+use crate::config::{Config, MacAddr, SensorFilter, SharedConfig};
+use crate::error::{ApiError, ErrorKind};
+use crate::ipmi::{
+    Bmc, BmcError, ChassisControl, ChassisStatus, LanParameter, PowerRestorePolicy, SensorReading,
+    SensorValue, SessionPool, ipmi_do,
+};
+use crate::metrics;
+use crate::query::deserialize_flag;
+use crate::tls::{self, ClientCertificate};
 
-use axum::Json;
-use axum::extract::{Path, State};
+use anyhow::Context as _;
+use axum::extract::{Path, Query, State};
+use axum::middleware::{from_fn, from_fn_with_state};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use axum_extra::middleware::option_layer;
 use futures::FutureExt;
-use futures::TryFutureExt;
 use futures::stream::{self, StreamExt};
+use http::{HeaderMap, StatusCode};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-
-use ipmi_rs::sensor_event::GetSensorReading;
+use std::collections::{BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostState {
     power_is_on: bool,
     power_restore_policy: String,
-    sensors: HashMap<String, String>,
+    sensors: HashMap<String, Sensor>,
+    /// The "last power event" flags set, which are watched for changes.
+    #[serde(skip)]
+    power_events: Vec<&'static str>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Error {
-    error: String,
+pub struct Sensor {
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entity: Option<String>,
+    lower_critical: Option<String>,
+    upper_critical: Option<String>,
+    /// Whether the reading is at or beyond a critical threshold.
+    alarm: bool,
+}
+
+/// The BMC's own network configuration. Parameters the BMC doesn't report
+/// are left out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LanConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_address: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip_address_source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac_address: Option<MacAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subnet_mask: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_gateway: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan_id: Option<u16>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct HostCommand {
     power: Option<bool>,
+    action: Option<PowerAction>,
+    /// The name of the host, when `safety.require_confirm` is set.
+    confirm: Option<String>,
+}
+
+impl HostCommand {
+    /// Whether the command may take a running host down.
+    fn is_destructive(&self) -> bool {
+        self.action.is_some() || self.power == Some(false)
+    }
+}
+
+/// The outcome of a power command: the host's state afterwards, or in a dry
+/// run its current state and what would have been sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResult {
+    #[serde(flatten)]
+    state: HostState,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    /// The command a dry run would have sent, if the host isn't already in
+    /// the requested state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    would_send: Option<ChassisControl>,
+}
+
+#[derive(Deserialize)]
+pub struct CommandParams {
+    /// Check the command and the host's reachability without sending it.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    dry_run: bool,
+}
+
+/// Actions which are always sent to the BMC, regardless of the current power state.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    Cycle,
+    Reset,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -48,41 +130,174 @@ where
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct HostList {
-    hosts: HashMap<String, Either<HostState, Error>>,
+impl<T, U> From<Result<T, U>> for Either<T, U>
+where
+    T: Serialize + for<'a> Deserialize<'a>,
+    U: Serialize + for<'a> Deserialize<'a>,
+{
+    fn from(result: Result<T, U>) -> Either<T, U> {
+        result.map_or_else(Either::right, Either::left)
+    }
+}
+
+pub struct HostsState {
+    config: SharedConfig,
+    sessions: Arc<SessionPool>,
+    /// The last poll of every host and when it started. Held while polling,
+    /// so concurrent requests share a single round of IPMI commands, except
+    /// by the background poller.
+    cache: tokio::sync::Mutex<Option<(Instant, PollResult)>>,
+    /// Held while the background poller or a fresh request polls for the
+    /// cache, with when the last such poll finished and whether it was fresh.
+    polling: tokio::sync::Mutex<Option<(Instant, bool)>>,
+    /// How often the background poller runs, if `/hosts` is answered from
+    /// its results.
+    monitor_interval: Option<Duration>,
+    /// Hosts which failed their last poll, and when to try them again.
+    backoff: std::sync::Mutex<HashMap<String, Backoff>>,
+    maintenance: Maintenance,
+    audit_log: Arc<AuditLog>,
+    /// The power event flags each host had on its last background poll.
+    power_events: std::sync::Mutex<HashMap<String, Vec<&'static str>>>,
+    notify_client: reqwest::Client,
+}
+
+/// Hosts someone is working on, which must not receive power commands.
+struct Maintenance {
+    path: PathBuf,
+    hosts: std::sync::Mutex<BTreeSet<String>>,
+    /// Held while saving, so concurrent updates are written in order.
+    saving: tokio::sync::Mutex<()>,
 }
 
-use ipmi_rs::Ipmi;
-use ipmi_rs::rmcp::Rmcp;
-use ipmi_rs::sensor_event::ThresholdReading;
-use ipmi_rs::storage::sdr::Record;
-use ipmi_rs::storage::sdr::event_reading_type_code::EventReadingTypeCodes;
+impl Maintenance {
+    fn load(path: PathBuf) -> anyhow::Result<Maintenance> {
+        let hosts = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Cannot parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Maintenance {
+            path,
+            hosts: std::sync::Mutex::new(hosts),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn contains(&self, hostname: &str) -> bool {
+        self.hosts.lock().unwrap().contains(hostname)
+    }
 
-fn read_host_state(ipmi: &mut Ipmi<Rmcp>) -> anyhow::Result<HostState> {
-    let chassis = ipmi
-        .send_recv(GetChassisStatus)
-        .map_err(|e| anyhow::anyhow!("{:?}", e))?;
-    let sensors: Vec<_> = ipmi.sdrs().collect();
+    /// Save the updated set, and only then make it visible, so a failed write
+    /// doesn't leave a flag in place that a restart would silently drop.
+    async fn set(&self, hostname: &str, enabled: bool) -> anyhow::Result<()> {
+        let _saving = self.saving.lock().await;
+        let mut hosts = self.hosts.lock().unwrap().clone();
+        if enabled {
+            hosts.insert(hostname.to_owned());
+        } else {
+            hosts.remove(hostname);
+        }
 
-    let extract_sensor = |s: &Record| {
-        let common = s.common_data()?;
-        if common.event_reading_type_code != EventReadingTypeCodes::Threshold {
-            return None;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
         }
+        // Write and rename, so a crash can't leave a truncated file behind.
+        let tmp = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&hosts)?).await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
 
-        let cmd = GetSensorReading::for_sensor_key(&common.key);
-        let raw = ipmi
-            .send_recv(cmd)
-            .map_err(|e| anyhow::anyhow!("{:?}", e))
-            .ok()?;
-        let reading = ThresholdReading::from(&raw);
+        *self.hosts.lock().unwrap() = hosts;
+        Ok(())
+    }
+}
 
-        let display = s.full_sensor()?.display_reading(reading.reading?)?;
-        Some((s.id()?.to_string(), display))
-    };
+/// Shortest and longest time an unreachable host is skipped for.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+    error: ApiError,
+}
+
+impl Backoff {
+    /// Double the delay with every consecutive failure, with some jitter so
+    /// hosts which went down together don't all get retried together.
+    fn delay(failures: u32) -> Duration {
+        let delay = MIN_BACKOFF.saturating_mul(1 << failures.saturating_sub(1).min(16));
+        delay
+            .min(MAX_BACKOFF)
+            .mul_f64(rand::random_range(0.75..1.0))
+    }
+}
+
+type PollResult = HashMap<String, Either<HostState, ApiError>>;
+
+type Hosts = Arc<HostsState>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostList<T = HostState>
+where
+    T: Serialize + for<'a> Deserialize<'a>,
+{
+    hosts: HashMap<String, Either<T, ApiError>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostSummary {
+    total: usize,
+    powered_on: usize,
+    /// Number of hosts whose BMC couldn't be reached. Hosts whose BMC
+    /// answered with an error are only listed in `problems`.
+    unreachable: usize,
+    /// Number of hosts with at least one sensor in alarm.
+    alarms: usize,
+    problems: Vec<Problem>,
+}
+
+/// A host which couldn't be polled or has sensors in alarm.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    alarms: Vec<String>,
+}
+
+fn read_sensor(s: SensorReading) -> Sensor {
+    // Not every BMC reports which thresholds have been crossed, so fall back
+    // to comparing the converted values ourselves.
+    let alarm = s.critical.unwrap_or_else(|| {
+        let beyond = |threshold: &Option<SensorValue>, above: bool| match (
+            s.reading.converted,
+            threshold.as_ref().and_then(|t| t.converted),
+        ) {
+            (Some(v), Some(t)) => (above && v >= t) || (!above && v <= t),
+            _ => false,
+        };
+        beyond(&s.upper_critical, true) || beyond(&s.lower_critical, false)
+    });
+
+    Sensor {
+        value: s.reading.display,
+        entity: s.entity,
+        lower_critical: s.lower_critical.map(|t| t.display),
+        upper_critical: s.upper_critical.map(|t| t.display),
+        alarm,
+    }
+}
 
-    let sensor_values = sensors.iter().filter_map(extract_sensor).collect();
+fn read_host_state(bmc: &mut dyn Bmc, filter: &SensorFilter) -> anyhow::Result<HostState> {
+    let chassis = bmc.chassis_status()?;
+    let sensors = bmc
+        .sensors(filter)
+        .into_iter()
+        .map(|s| (s.name.clone(), read_sensor(s)))
+        .collect();
 
     Ok(HostState {
         power_is_on: chassis.power_is_on,
@@ -91,77 +306,1130 @@ fn read_host_state(ipmi: &mut Ipmi<Rmcp>) -> anyhow::Result<HostState> {
             PowerRestorePolicy::AlwaysOff => "always-off".to_owned(),
             PowerRestorePolicy::Previous => "previous".to_owned(),
         },
-        sensors: sensor_values,
+        sensors,
+        power_events: power_events(&chassis),
+    })
+}
+
+/// Read the BMC's LAN configuration. Parameters the BMC doesn't support are
+/// left out, but any other failure fails the whole request.
+fn read_lan_config(bmc: &mut dyn Bmc) -> anyhow::Result<LanConfig> {
+    let mut get = |parameter| {
+        bmc.lan_parameter(parameter)
+            .with_context(|| format!("cannot read LAN parameter {parameter:?}"))
+    };
+    let ipv4 = |data: Vec<u8>| match data[..] {
+        [a, b, c, d, ..] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    };
+
+    Ok(LanConfig {
+        ip_address: get(LanParameter::IpAddress)?.and_then(ipv4),
+        ip_address_source: get(LanParameter::IpAddressSource)?.map(|data| {
+            match data[0] & 0x0f {
+                0 => "unspecified",
+                1 => "static",
+                2 => "dhcp",
+                3 => "bios",
+                _ => "other",
+            }
+            .to_owned()
+        }),
+        mac_address: get(LanParameter::MacAddress)?.and_then(|data| match data[..] {
+            [a, b, c, d, e, f, ..] => Some(MacAddr::from([a, b, c, d, e, f])),
+            _ => None,
+        }),
+        subnet_mask: get(LanParameter::SubnetMask)?.and_then(ipv4),
+        default_gateway: get(LanParameter::DefaultGateway)?.and_then(ipv4),
+        vlan_id: get(LanParameter::VlanId)?.and_then(|data| match data[..] {
+            // The top bit of the second byte says whether VLANs are enabled.
+            [low, high, ..] if high & 0x80 != 0 => {
+                Some(u16::from(high & 0x0f) << 8 | u16::from(low))
+            }
+            _ => None,
+        }),
     })
 }
 
+/// Report a failure to talk to a host's BMC, saying whether it could be
+/// reached at all.
+fn bmc_error(e: BmcError) -> ApiError {
+    let (kind, e) = match e {
+        BmcError::Unreachable(e) => (ErrorKind::Unreachable, e),
+        BmcError::Command(e) => (ErrorKind::CommandError, e),
+    };
+    ApiError::new(StatusCode::BAD_GATEWAY, format!("{:?}", e)).with_kind(kind)
+}
+
+pub async fn ipmi_host_lan_handler(
+    Path(hostname): Path<String>,
+    State(state): State<Hosts>,
+) -> Result<Json<LanConfig>, ApiError> {
+    let config = state.config.load_full();
+    let Some(host) = config.host.get(&hostname) else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invalid host"));
+    };
+
+    ipmi_do(&state.sessions, host, &config.ipmi, |ipmi| {
+        read_lan_config(ipmi)
+    })
+    .await
+    .map(Json)
+    .map_err(bmc_error)
+}
+
 pub async fn ipmi_host_get_handler(
     Path(hostname): Path<String>,
-    State(config): State<Config>,
-) -> Json<Either<HostState, Error>> {
+    State(state): State<Hosts>,
+) -> Result<Json<HostState>, ApiError> {
+    let config = state.config.load_full();
     let Some(host) = config.host.get(&hostname) else {
-        return Json(Either::right(Error {
-            error: "invalid host".to_string(),
-        }));
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invalid host"));
     };
 
-    let result = ipmi_do(
-        &host.address,
-        &config.ipmi.username,
-        config.ipmi.password.as_ref().unwrap().as_bytes(),
-        read_host_state,
-    )
-    .map_err(|e| Error {
-        error: format!("{:?}", e),
+    let filter = config.sensor_filter(host);
+    ipmi_do(&state.sessions, host, &config.ipmi, move |ipmi| {
+        read_host_state(ipmi, &filter)
     })
-    .map_ok_or_else(Either::right, Either::left)
-    .await;
-    Json(result)
+    .await
+    .map(Json)
+    .map_err(bmc_error)
 }
 
-pub async fn ipmi_hosts_handler(State(config): State<Config>) -> Json<HostList> {
-    let hosts = stream::iter(config.host)
+/// Poll every host. Hosts which failed recently are skipped, and reported
+/// with their last error, until their backoff expires or `fresh` is set.
+async fn poll_hosts(state: &HostsState, fresh: bool) -> PollResult {
+    let config = state.config.load_full();
+    let now = Instant::now();
+    let _timer = metrics::HOSTS_POLL_DURATION.start_timer();
+    let mut skipped = PollResult::new();
+    let mut targets = Vec::new();
+    {
+        let backoff = state.backoff.lock().unwrap();
+        for (hostname, host) in &config.host {
+            match backoff.get(hostname) {
+                Some(b) if !fresh && b.retry_at > now => {
+                    let error = ApiError {
+                        error: format!(
+                            "{} (skipped for another {}s)",
+                            b.error.error,
+                            (b.retry_at - now).as_secs()
+                        ),
+                        ..b.error.clone()
+                    };
+                    skipped.insert(hostname.clone(), Either::right(error));
+                }
+                _ => targets.push((hostname.clone(), host.clone())),
+            }
+        }
+    }
+
+    let mut results: PollResult = stream::iter(targets)
         .map(|(hostname, host)| {
-            ipmi_do(
-                &host.address,
-                &config.ipmi.username,
-                config.ipmi.password.as_ref().unwrap().as_bytes(),
-                read_host_state,
-            )
-            .map_err(|e| Error {
-                error: format!("{:?}", e),
+            let filter = config.sensor_filter(&host);
+            ipmi_do(&state.sessions, &host, &config.ipmi, move |ipmi| {
+                read_host_state(ipmi, &filter)
             })
-            .map_ok_or_else(Either::right, Either::left)
             .map(move |v| (hostname, v))
         })
         .buffer_unordered(4)
+        .map(|(hostname, result)| {
+            let mut backoff = state.backoff.lock().unwrap();
+            let result = match result {
+                Ok(host_state) => {
+                    backoff.remove(&hostname);
+                    Either::left(host_state)
+                }
+                Err(e) => {
+                    let error = bmc_error(e);
+                    let kind = match error.kind {
+                        Some(ErrorKind::Unreachable) => "unreachable",
+                        _ => "command_error",
+                    };
+                    metrics::HOSTS_POLL_FAILURES
+                        .with_label_values(&[kind])
+                        .inc();
+                    let failures = backoff.get(&hostname).map_or(0, |b| b.failures) + 1;
+                    backoff.insert(
+                        hostname.clone(),
+                        Backoff {
+                            failures,
+                            retry_at: Instant::now() + Backoff::delay(failures),
+                            error: error.clone(),
+                        },
+                    );
+                    Either::right(error)
+                }
+            };
+            (hostname, result)
+        })
         .collect()
         .await;
 
-    Json(HostList { hosts })
+    results.extend(skipped);
+    results
+}
+
+/// Poll every host and keep the result for the next request, without holding
+/// the cache meanwhile so it can be read from. Only one such poll runs at a
+/// time, and callers which waited for one share its result if it is at least
+/// as fresh as what they asked for, so BMCs aren't polled once per request.
+async fn monitor_poll_hosts(state: &HostsState, fresh: bool) -> PollResult {
+    let requested_at = Instant::now();
+    let mut polling = state.polling.lock().await;
+    if let Some((finished_at, was_fresh)) = *polling
+        && finished_at > requested_at
+        && (was_fresh || !fresh)
+        && let Some((_, hosts)) = &*state.cache.lock().await
+    {
+        return hosts.clone();
+    }
+
+    let polled_at = Instant::now();
+    let hosts = poll_hosts(state, fresh).await;
+    let mut cache = state.cache.lock().await;
+    // Don't replace a newer result stored meanwhile.
+    if cache.as_ref().is_none_or(|(at, _)| *at < polled_at) {
+        *cache = Some((polled_at, hosts.clone()));
+    }
+    *polling = Some((Instant::now(), fresh));
+    notify_power_events(state, &hosts);
+    hosts
 }
 
+/// Poll every host in the background every `interval`.
+async fn monitor_hosts(state: Hosts, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        monitor_poll_hosts(&state, false).await;
+    }
+}
+
+/// How long clients are told to wait while the first background poll runs.
+const FIRST_POLL_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// The background poller hasn't finished its first round yet. Answering with
+/// no hosts would look like an empty fleet.
+pub struct NotReady;
+
+impl IntoResponse for NotReady {
+    fn into_response(self) -> Response {
+        (
+            [(
+                http::header::RETRY_AFTER,
+                FIRST_POLL_RETRY_AFTER.as_secs().to_string(),
+            )],
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the hosts are still being polled for the first time",
+            ),
+        )
+            .into_response()
+    }
+}
+
+/// The latest results of the background poller, if it runs, or else poll
+/// every host unless `ipmi.cache_ttl_secs` is set and the last poll is
+/// recent enough. `fresh` always polls.
+async fn cached_poll_hosts(state: &HostsState, fresh: bool) -> Result<PollResult, NotReady> {
+    if state.monitor_interval.is_some() {
+        if fresh {
+            return Ok(monitor_poll_hosts(state, fresh).await);
+        }
+        return match &*state.cache.lock().await {
+            Some((_, hosts)) => Ok(hosts.clone()),
+            None => Err(NotReady),
+        };
+    }
+
+    let Some(ttl) = state.config.load().ipmi.cache_ttl_secs else {
+        return Ok(poll_hosts(state, fresh).await);
+    };
+
+    let mut cache = state.cache.lock().await;
+    if let Some((polled_at, hosts)) = &*cache
+        && !fresh
+        && polled_at.elapsed() < Duration::from_secs(ttl)
+    {
+        return Ok(hosts.clone());
+    }
+
+    let polled_at = Instant::now();
+    let hosts = poll_hosts(state, fresh).await;
+    *cache = Some((polled_at, hosts.clone()));
+    Ok(hosts)
+}
+
+#[derive(Deserialize)]
+pub struct PollParams {
+    /// Bypass the cache and query every BMC, even those backing off.
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    fresh: bool,
+}
+
+pub async fn ipmi_hosts_handler(
+    State(state): State<Hosts>,
+    Query(PollParams { fresh }): Query<PollParams>,
+) -> Result<Json<HostList>, NotReady> {
+    let hosts = cached_poll_hosts(&state, fresh).await?;
+    Ok(Json(HostList { hosts }))
+}
+
+/// Aggregate counts over all hosts, for dashboards which only need to know
+/// whether anything is wrong.
+pub async fn ipmi_hosts_summary_handler(
+    State(state): State<Hosts>,
+    Query(PollParams { fresh }): Query<PollParams>,
+) -> Result<Json<HostSummary>, NotReady> {
+    let hosts = cached_poll_hosts(&state, fresh).await?;
+    Ok(Json(summarize(hosts)))
+}
+
+fn summarize(hosts: PollResult) -> HostSummary {
+    let mut summary = HostSummary {
+        total: hosts.len(),
+        powered_on: 0,
+        unreachable: 0,
+        alarms: 0,
+        problems: Vec::new(),
+    };
+    for (host, result) in hosts {
+        match result.0 {
+            either::Either::Left(state) => {
+                if state.power_is_on {
+                    summary.powered_on += 1;
+                }
+                let mut alarms: Vec<_> = state
+                    .sensors
+                    .into_iter()
+                    .filter(|(_, sensor)| sensor.alarm)
+                    .map(|(name, _)| name)
+                    .collect();
+                if !alarms.is_empty() {
+                    alarms.sort();
+                    summary.alarms += 1;
+                    summary.problems.push(Problem {
+                        host,
+                        error: None,
+                        alarms,
+                    });
+                }
+            }
+            either::Either::Right(ApiError { error, kind, .. }) => {
+                if kind == Some(ErrorKind::Unreachable) {
+                    summary.unreachable += 1;
+                }
+                summary.problems.push(Problem {
+                    host,
+                    error: Some(error),
+                    alarms: Vec::new(),
+                });
+            }
+        }
+    }
+    summary.problems.sort_by(|a, b| a.host.cmp(&b.host));
+    summary
+}
+
+/// Issue a power command and return the host's state afterwards.
+///
+/// BMCs take a moment to reflect a power-up, so `power_is_on` may still read
+/// false unless `ipmi.readback_delay_ms` is set.
 pub async fn ipmi_host_put_handler(
     Path(hostname): Path<String>,
-    State(config): State<Config>,
+    State(state): State<Hosts>,
+    Query(CommandParams { dry_run }): Query<CommandParams>,
+    headers: HeaderMap,
     Json(body): Json<HostCommand>,
-) {
-    let host = &config.host[&hostname];
+) -> Result<Json<CommandResult>, ApiError> {
+    let config = state.config.load_full();
+    let confirm = headers
+        .get("x-confirm-host")
+        .and_then(|v| v.to_str().ok())
+        .or(body.confirm.as_deref())
+        .map(|c| c == hostname);
+    send_command(&state, &config, &hostname, &body, confirm, dry_run)
+        .await
+        .map(Json)
+}
 
-    let cmd = match body.power {
-        Some(true) => Some(ChassisControl::PowerUp),
-        Some(false) => Some(ChassisControl::PowerDown),
-        None => None,
+/// Send `command` to `hostname`, after checking it may receive one.
+/// `confirmed` says whether the request named the host, for
+/// `safety.require_confirm`. A `dry_run` goes as far as reading the host's
+/// state from its BMC, but stops short of sending the command.
+async fn send_command(
+    state: &HostsState,
+    config: &Config,
+    hostname: &str,
+    command: &HostCommand,
+    confirmed: Option<bool>,
+    dry_run: bool,
+) -> Result<CommandResult, ApiError> {
+    let Some(host) = config.host.get(hostname) else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invalid host"));
     };
 
-    if let Some(cmd) = cmd {
-        ipmi_do(
-            &host.address,
-            &config.ipmi.username,
-            config.ipmi.password.as_ref().unwrap().as_bytes(),
-            move |ipmi| ipmi.send_recv(cmd).map_err(|e| anyhow::anyhow!("{:?}", e)),
+    if state.maintenance.contains(hostname) {
+        return Err(ApiError::new(
+            StatusCode::LOCKED,
+            format!("{hostname} is in maintenance"),
+        ));
+    }
+
+    if config.safety.require_confirm && command.is_destructive() && confirmed != Some(true) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("confirm the command by setting X-Confirm-Host or confirm to {hostname}"),
+        ));
+    }
+
+    let action = command.action.map(|action| match action {
+        PowerAction::Cycle => ChassisControl::PowerCycle,
+        PowerAction::Reset => ChassisControl::HardReset,
+    });
+    let power = command.power;
+    let filter = config.sensor_filter(host);
+    let delay = Duration::from_millis(config.ipmi.readback_delay_ms);
+
+    ipmi_do(&state.sessions, host, &config.ipmi, move |ipmi| {
+        apply_command(ipmi, action, power, &filter, delay, dry_run)
+    })
+    .await
+    .map_err(bmc_error)
+}
+
+fn apply_command(
+    bmc: &mut dyn Bmc,
+    action: Option<ChassisControl>,
+    power: Option<bool>,
+    filter: &SensorFilter,
+    delay: Duration,
+    dry_run: bool,
+) -> anyhow::Result<CommandResult> {
+    // Turning a host on or off is a no-op if it is already in the
+    // requested state, which some BMCs don't handle gracefully.
+    let cmd = match (action, power) {
+        (Some(cmd), _) => Some(cmd),
+        (None, Some(power)) => {
+            let chassis = bmc.chassis_status()?;
+            match (chassis.power_is_on, power) {
+                (false, true) => Some(ChassisControl::PowerUp),
+                (true, false) => Some(ChassisControl::PowerDown),
+                _ => None,
+            }
+        }
+        (None, None) => None,
+    };
+
+    if let Some(cmd) = cmd
+        && !dry_run
+    {
+        bmc.chassis_control(cmd)?;
+        std::thread::sleep(delay);
+    }
+    read_host_state(bmc, filter).map(|state| CommandResult {
+        state,
+        dry_run,
+        would_send: cmd.filter(|_| dry_run),
+    })
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchAction {
+    On,
+    Off,
+    Cycle,
+    Reset,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BatchCommand {
+    hosts: Vec<String>,
+    action: BatchAction,
+    /// The names of the hosts, when `safety.require_confirm` is set.
+    #[serde(default)]
+    confirm: Vec<String>,
+}
+
+impl From<BatchAction> for HostCommand {
+    fn from(action: BatchAction) -> HostCommand {
+        let (power, action) = match action {
+            BatchAction::On => (Some(true), None),
+            BatchAction::Off => (Some(false), None),
+            BatchAction::Cycle => (None, Some(PowerAction::Cycle)),
+            BatchAction::Reset => (None, Some(PowerAction::Reset)),
+        };
+        HostCommand {
+            power,
+            action,
+            confirm: None,
+        }
+    }
+}
+
+/// Issue the same power command to several hosts at once. Each host is
+/// audited separately, and its outcome reported in the result.
+pub async fn ipmi_hosts_power_handler(
+    State(state): State<Hosts>,
+    Query(CommandParams { dry_run }): Query<CommandParams>,
+    certificate: Option<Extension<Option<ClientCertificate>>>,
+    headers: HeaderMap,
+    Json(body): Json<BatchCommand>,
+) -> Json<HostList<CommandResult>> {
+    let config = state.config.load_full();
+    let principal = certificate
+        .as_ref()
+        .and_then(|Extension(c)| c.as_ref())
+        .and_then(|c| c.common_name.as_deref());
+    let confirmed: BTreeSet<&str> = headers
+        .get("x-confirm-host")
+        .and_then(|v| v.to_str().ok())
+        .into_iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .chain(body.confirm.iter().map(String::as_str))
+        .collect();
+    let command = HostCommand::from(body.action);
+    let hostnames: BTreeSet<String> = body.hosts.into_iter().collect();
+
+    let hosts = stream::iter(hostnames)
+        .map(|hostname| {
+            let confirmed = Some(confirmed.contains(hostname.as_str()));
+            let (state, config, command) = (&state, &config, &command);
+            async move {
+                let result =
+                    send_command(state, config, &hostname, command, confirmed, dry_run).await;
+                (hostname, result)
+            }
+        })
+        .buffer_unordered(4)
+        .map(|(hostname, result)| {
+            state.audit_log.record_command(
+                &hostname,
+                serde_json::json!({ "action": body.action }),
+                principal,
+                result
+                    .as_ref()
+                    .map_or_else(|e| e.status, |_| StatusCode::OK),
+                dry_run,
+            );
+            (hostname, Either::from(result))
+        })
+        .collect()
+        .await;
+
+    Json(HostList { hosts })
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MaintenanceState {
+    maintenance: bool,
+}
+
+pub async fn maintenance_get_handler(
+    Path(hostname): Path<String>,
+    State(state): State<Hosts>,
+) -> Result<Json<MaintenanceState>, ApiError> {
+    if !state.config.load().host.contains_key(&hostname) {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invalid host"));
+    }
+
+    let maintenance = state.maintenance.contains(&hostname);
+    Ok(Json(MaintenanceState { maintenance }))
+}
+
+/// Put a host in or out of maintenance. Hosts in maintenance reject all
+/// power commands, but can still be read.
+pub async fn maintenance_put_handler(
+    Path(hostname): Path<String>,
+    State(state): State<Hosts>,
+    Json(body): Json<MaintenanceState>,
+) -> Result<Json<MaintenanceState>, ApiError> {
+    if !state.config.load().host.contains_key(&hostname) {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invalid host"));
+    }
+
+    match state.maintenance.set(&hostname, body.maintenance).await {
+        Ok(()) => Ok(Json(body)),
+        Err(e) => Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("cannot save maintenance state: {e:?}"),
+        )),
+    }
+}
+
+/// How long the webhook gets to accept a power event notification.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+struct PowerEvent<'a> {
+    timestamp: String,
+    host: &'a str,
+    events: Vec<&'static str>,
+    power_is_on: bool,
+}
+
+/// The "last power event" flags set in `status`.
+fn power_events(status: &ChassisStatus) -> Vec<&'static str> {
+    [
+        (status.ac_failed, "ac_failed"),
+        (status.last_power_on_by_fault, "power_on_by_fault"),
+        (status.last_power_on_by_overload, "power_overload"),
+        (
+            status.last_power_on_by_interlock_activated,
+            "interlock_activated",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect()
+}
+
+/// Notify the webhook of the power event flags which became set on a host
+/// since the last background poll. Flags already set when a host is first
+/// polled are not reported, and hosts which couldn't be polled keep their
+/// previous flags.
+fn notify_power_events(state: &HostsState, hosts: &PollResult) {
+    let config = state.config.load();
+    let mut previous = state.power_events.lock().unwrap();
+    for (hostname, result) in hosts {
+        let either::Either::Left(host) = &result.0 else {
+            continue;
+        };
+        let new: Vec<_> = match previous.insert(hostname.clone(), host.power_events.clone()) {
+            Some(before) => host
+                .power_events
+                .iter()
+                .filter(|e| !before.contains(e))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+        if new.is_empty() {
+            continue;
+        }
+
+        tracing::warn!(hostname, ?new, "unexpected power event");
+        let Some(notify) = &config.notify else {
+            continue;
+        };
+        let event = PowerEvent {
+            timestamp: OffsetDateTime::now_utc().format(&Rfc3339).unwrap(),
+            host: hostname,
+            events: new,
+            power_is_on: host.power_is_on,
+        };
+        // Sent in the background, so a slow webhook doesn't hold up polling.
+        let request = state.notify_client.post(notify.url.clone()).json(&event);
+        let hostname = hostname.clone();
+        tokio::spawn(async move {
+            let result = request.send().await.and_then(|r| r.error_for_status());
+            if let Err(e) = result {
+                tracing::error!(hostname, "cannot send power event notification: {e}");
+            }
+        });
+    }
+}
+
+pub fn router<S>(
+    config: SharedConfig,
+    require_client_cert: bool,
+    audit_log: Arc<AuditLog>,
+) -> anyhow::Result<axum::Router<S>> {
+    use axum::routing::{get, post, put};
+
+    let maintenance = Maintenance::load(config.load().maintenance_file.clone())?;
+
+    let sessions = Arc::new(SessionPool::new(Duration::from_secs(
+        config.load().ipmi.session_idle_secs,
+    )));
+    let monitor_interval = config
+        .load()
+        .monitor
+        .as_ref()
+        .map(|m| Duration::from_secs(m.interval_secs));
+    // Power events are found by the background poller, so it runs for them
+    // even if `/hosts` isn't answered from its results.
+    let poll_interval = monitor_interval.or_else(|| {
+        let notify = config.load().notify.clone()?;
+        Some(Duration::from_secs(notify.interval_secs))
+    });
+    let state = Hosts::new(HostsState {
+        config,
+        sessions,
+        cache: tokio::sync::Mutex::new(None),
+        polling: tokio::sync::Mutex::new(None),
+        monitor_interval,
+        backoff: std::sync::Mutex::new(HashMap::new()),
+        maintenance,
+        audit_log: audit_log.clone(),
+        power_events: std::sync::Mutex::new(HashMap::new()),
+        notify_client: reqwest::Client::builder().timeout(NOTIFY_TIMEOUT).build()?,
+    });
+    if let Some(interval) = poll_interval {
+        tokio::spawn(monitor_hosts(state.clone(), interval));
+    }
+
+    let client_cert_layer =
+        || option_layer(require_client_cert.then(|| from_fn(tls::require_client_certificate)));
+
+    Ok(axum::Router::new()
+        .route("/hosts", get(ipmi_hosts_handler))
+        .route("/hosts/summary", get(ipmi_hosts_summary_handler))
+        .route(
+            "/hosts/power",
+            post(ipmi_hosts_power_handler).route_layer(client_cert_layer()),
         )
-        .await
+        .route("/host/{hostname}", get(ipmi_host_get_handler))
+        .route("/host/{hostname}/lan", get(ipmi_host_lan_handler))
+        .route(
+            "/host/{hostname}/command",
+            put(ipmi_host_put_handler)
+                .route_layer(client_cert_layer())
+                .route_layer(from_fn_with_state(audit_log.clone(), audit::audit)),
+        )
+        .route(
+            "/host/{hostname}/maintenance",
+            get(maintenance_get_handler).merge(
+                put(maintenance_put_handler)
+                    .route_layer(client_cert_layer())
+                    .route_layer(from_fn_with_state(audit_log, audit::audit)),
+            ),
+        )
+        .with_state(state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Either, HostState, HostsState, Maintenance, PollParams, PollResult, apply_command,
+        ipmi_hosts_handler, monitor_poll_hosts, notify_power_events, poll_hosts, read_host_state,
+        read_lan_config, summarize,
+    };
+    use crate::audit::AuditLog;
+    use crate::config::{Config, SensorFilter};
+    use crate::error::{ApiError, ErrorKind};
+    use crate::ipmi::{
+        Bmc, BmcError, ChassisControl, ChassisStatus, LanParameter, PowerRestorePolicy,
+        SensorReading, SensorValue, SessionPool, Tracked,
+    };
+    use crate::metrics;
+    use axum::extract::{Query, State};
+    use axum::response::IntoResponse;
+    use http::StatusCode;
+    use ipmi_rs::Ipmi;
+    use ipmi_rs::rmcp::Rmcp;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// A BMC answering with canned chassis status and sensors.
+    struct FakeBmc {
+        chassis: ChassisStatus,
+        sensors: Vec<SensorReading>,
+        sent: Vec<ChassisControl>,
+        /// LAN parameters by selector, or `None` if reading them fails.
+        lan: Option<HashMap<u8, Vec<u8>>>,
+        /// How many chassis status reads fail before they start succeeding.
+        failing_reads: usize,
+    }
+
+    impl Bmc for FakeBmc {
+        fn chassis_status(&mut self) -> anyhow::Result<ChassisStatus> {
+            if self.failing_reads > 0 {
+                self.failing_reads -= 1;
+                anyhow::bail!("timed out");
+            }
+            Ok(self.chassis)
+        }
+
+        fn chassis_control(&mut self, control: ChassisControl) -> anyhow::Result<()> {
+            self.sent.push(control);
+            Ok(())
+        }
+
+        fn lan_parameter(&mut self, parameter: LanParameter) -> anyhow::Result<Option<Vec<u8>>> {
+            match &self.lan {
+                Some(lan) => Ok(lan.get(&(parameter as u8)).cloned()),
+                None => anyhow::bail!("timed out"),
+            }
+        }
+
+        fn sensors(&mut self, filter: &SensorFilter) -> Vec<SensorReading> {
+            self.sensors
+                .iter()
+                .filter(|s| filter.matches(&s.name))
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn chassis(power_is_on: bool, power_restore_policy: PowerRestorePolicy) -> ChassisStatus {
+        ChassisStatus {
+            power_restore_policy,
+            power_control_fault: false,
+            power_fault: false,
+            interlock: false,
+            power_overload: false,
+            power_is_on,
+            last_power_on_by_command: false,
+            last_power_on_by_fault: false,
+            last_power_on_by_interlock_activated: false,
+            last_power_on_by_overload: false,
+            ac_failed: false,
+        }
+    }
+
+    fn value(display: &str, converted: f32) -> SensorValue {
+        SensorValue {
+            display: display.to_owned(),
+            converted: Some(converted),
+        }
+    }
+
+    fn sensor(name: &str, reading: f32, critical: Option<bool>) -> SensorReading {
+        SensorReading {
+            name: name.to_owned(),
+            entity: None,
+            reading: value(&format!("{reading} °C"), reading),
+            lower_critical: Some(value("5 °C", 5.0)),
+            upper_critical: Some(value("90 °C", 90.0)),
+            critical,
+        }
+    }
+
+    #[test]
+    fn host_state_from_bmc() {
+        for (policy, expected) in [
+            (PowerRestorePolicy::AlwaysOn, "always-on"),
+            (PowerRestorePolicy::AlwaysOff, "always-off"),
+            (PowerRestorePolicy::Previous, "previous"),
+        ] {
+            let mut bmc = FakeBmc {
+                chassis: chassis(true, policy),
+                sensors: vec![],
+                sent: vec![],
+                lan: None,
+                failing_reads: 0,
+            };
+            let state = read_host_state(&mut bmc, &SensorFilter::default()).unwrap();
+            assert!(state.power_is_on);
+            assert_eq!(state.power_restore_policy, expected);
+        }
+    }
+
+    #[test]
+    fn sensor_alarms() {
+        let mut bmc = FakeBmc {
+            chassis: chassis(false, PowerRestorePolicy::Previous),
+            sensors: vec![
+                sensor("CPU", 40.0, None),
+                sensor("Inlet", 95.0, None),
+                sensor("Outlet", 2.0, None),
+                // What the BMC says takes precedence over the thresholds.
+                sensor("PCH", 40.0, Some(true)),
+                sensor("DIMM", 95.0, Some(false)),
+                sensor("Ignored", 95.0, None),
+            ],
+            sent: vec![],
+            lan: None,
+            failing_reads: 0,
+        };
+        let filter = SensorFilter {
+            deny: vec!["Ignored".to_owned()],
+            ..Default::default()
+        };
+        let state = read_host_state(&mut bmc, &filter).unwrap();
+        assert!(!state.power_is_on);
+
+        let cpu = &state.sensors["CPU"];
+        assert_eq!(cpu.value, "40 °C");
+        assert_eq!(cpu.lower_critical.as_deref(), Some("5 °C"));
+        assert_eq!(cpu.upper_critical.as_deref(), Some("90 °C"));
+
+        let alarms = |name: &str| state.sensors[name].alarm;
+        assert!(!alarms("CPU"));
+        assert!(alarms("Inlet"));
+        assert!(alarms("Outlet"));
+        assert!(alarms("PCH"));
+        assert!(!alarms("DIMM"));
+        assert!(!state.sensors.contains_key("Ignored"));
+    }
+
+    #[test]
+    fn lan_config_skips_unsupported_parameters() {
+        let mut bmc = FakeBmc {
+            chassis: chassis(false, PowerRestorePolicy::Previous),
+            sensors: vec![],
+            sent: vec![],
+            lan: Some(HashMap::from([
+                (LanParameter::IpAddress as u8, vec![10, 0, 0, 5]),
+                (LanParameter::IpAddressSource as u8, vec![2]),
+            ])),
+            failing_reads: 0,
+        };
+        let lan = read_lan_config(&mut bmc).unwrap();
+        assert_eq!(lan.ip_address, Some("10.0.0.5".parse().unwrap()));
+        assert_eq!(lan.ip_address_source.as_deref(), Some("dhcp"));
+        assert_eq!(lan.mac_address, None);
+
+        // Other failures aren't mistaken for missing parameters.
+        bmc.lan = None;
+        assert!(read_lan_config(&mut bmc).is_err());
+    }
+
+    #[test]
+    fn dry_run_sends_nothing() {
+        let mut bmc = FakeBmc {
+            chassis: chassis(false, PowerRestorePolicy::Previous),
+            sensors: vec![],
+            sent: vec![],
+            lan: None,
+            failing_reads: 0,
+        };
+        let filter = SensorFilter::default();
+        let delay = Duration::ZERO;
+
+        let result = apply_command(&mut bmc, None, Some(true), &filter, delay, true).unwrap();
+        assert!(result.dry_run);
+        assert!(matches!(result.would_send, Some(ChassisControl::PowerUp)));
+        assert!(bmc.sent.is_empty());
+
+        // Nothing would be sent to a host already in the requested state.
+        let result = apply_command(&mut bmc, None, Some(false), &filter, delay, true).unwrap();
+        assert!(result.would_send.is_none());
+
+        let result = apply_command(&mut bmc, None, Some(true), &filter, delay, false).unwrap();
+        assert!(!result.dry_run);
+        assert!(result.would_send.is_none());
+        assert!(matches!(bmc.sent[..], [ChassisControl::PowerUp]));
+    }
+
+    /// A power cycle whose read-back fails on a pooled session, as it does
+    /// when the BMC resets along with the host, isn't sent again.
+    #[test]
+    fn sent_power_command_is_not_retried() {
+        let session = || {
+            Ok(Ipmi::new(
+                Rmcp::new(("127.0.0.1", 9), Duration::from_secs(1)).unwrap(),
+            ))
+        };
+        let pool = SessionPool::new(Duration::from_secs(60));
+        pool.run("bmc", 1, session, |ipmi| {
+            (Ok::<_, anyhow::Error>(()), ipmi, false)
+        })
         .unwrap();
+
+        let mut bmc = FakeBmc {
+            chassis: chassis(true, PowerRestorePolicy::Previous),
+            sensors: vec![],
+            sent: vec![],
+            lan: None,
+            failing_reads: 1,
+        };
+        let result = pool.run("bmc", 1, session, |ipmi| {
+            let mut tracked = Tracked::new(&mut bmc);
+            let result = apply_command(
+                &mut tracked,
+                Some(ChassisControl::PowerCycle),
+                None,
+                &SensorFilter::default(),
+                Duration::ZERO,
+                false,
+            );
+            (result, ipmi, tracked.control_sent)
+        });
+        assert!(matches!(result, Err(BmcError::Command(_))));
+        assert!(matches!(bmc.sent[..], [ChassisControl::PowerCycle]));
+    }
+
+    /// Hosts state with the background poller enabled, but not running.
+    fn hosts_state(dir: &std::path::Path, hosts: &str) -> Arc<HostsState> {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [ipmi]
+            username = "admin"
+            password = "hunter2"
+
+            [pxe]
+            caches = []
+            cachix = []
+            store = "/nonexistent"
+
+            {hosts}
+            "#,
+        ))
+        .unwrap();
+        Arc::new(HostsState {
+            config: Arc::new(arc_swap::ArcSwap::from_pointee(config)),
+            sessions: Arc::new(SessionPool::new(Duration::ZERO)),
+            cache: tokio::sync::Mutex::new(None),
+            polling: tokio::sync::Mutex::new(None),
+            monitor_interval: Some(Duration::from_secs(30)),
+            backoff: std::sync::Mutex::new(HashMap::new()),
+            maintenance: Maintenance::load(dir.join("maintenance.json")).unwrap(),
+            audit_log: Arc::new(AuditLog::open(None).unwrap()),
+            power_events: std::sync::Mutex::new(HashMap::new()),
+            notify_client: reqwest::Client::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn hosts_unavailable_until_first_poll() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = hosts_state(dir.path(), "");
+        let hosts = async || {
+            ipmi_hosts_handler(State(state.clone()), Query(PollParams { fresh: false }))
+                .await
+                .into_response()
+        };
+
+        let response = hosts().await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "5");
+
+        monitor_poll_hosts(&state, false).await;
+        assert_eq!(hosts().await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn fresh_polls_share_a_running_poll() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = hosts_state(dir.path(), "");
+
+        // Stand in for a poll already under way, which finds a host that a
+        // poll of this config would not.
+        let mut polling = state.polling.lock().await;
+        let requests: Vec<_> = (0..2)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { monitor_poll_hosts(&state, true).await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let error = ApiError::new(StatusCode::BAD_GATEWAY, "timed out");
+        let hosts = PollResult::from([("node1".to_owned(), Either::right(error))]);
+        *state.cache.lock().await = Some((Instant::now(), hosts));
+        *polling = Some((Instant::now(), true));
+        drop(polling);
+
+        for request in requests {
+            assert!(request.await.unwrap().contains_key("node1"));
+        }
+
+        // A poll which started before the cached one doesn't replace it.
+        let later = Instant::now() + Duration::from_secs(60);
+        state.cache.lock().await.as_mut().unwrap().0 = later;
+        *state.polling.lock().await = None;
+        assert!(monitor_poll_hosts(&state, true).await.is_empty());
+        let cache = state.cache.lock().await;
+        assert!(cache.as_ref().unwrap().1.contains_key("node1"));
+    }
+
+    #[tokio::test]
+    async fn poll_failures_are_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = hosts_state(
+            dir.path(),
+            r#"
+            [host.node1]
+            address = "bmc.invalid"
+            "#,
+        );
+        let failures = || {
+            metrics::HOSTS_POLL_FAILURES
+                .with_label_values(&["unreachable"])
+                .get()
+        };
+        let polls = metrics::HOSTS_POLL_DURATION.get_sample_count();
+        let before = failures();
+
+        let hosts = poll_hosts(&state, false).await;
+        let error = hosts["node1"].0.as_ref().right().unwrap();
+        assert_eq!(error.kind, Some(ErrorKind::Unreachable));
+        assert_eq!(failures(), before + 1);
+
+        // The host is backing off, and isn't polled or counted again.
+        let hosts = poll_hosts(&state, false).await;
+        assert!(hosts["node1"].0.as_ref().right().is_some());
+        assert_eq!(failures(), before + 1);
+        assert!(metrics::HOSTS_POLL_DURATION.get_sample_count() >= polls + 2);
+    }
+
+    #[tokio::test]
+    async fn new_power_events_are_notified() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = axum::Router::new().fallback(move |body: axum::Json<serde_json::Value>| {
+            tx.send(body.0).unwrap();
+            std::future::ready(())
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = hosts_state(dir.path(), &format!("[notify]\nurl = \"{url}\""));
+        let poll = |events: Vec<&'static str>| {
+            let host = HostState {
+                power_is_on: true,
+                power_restore_policy: "previous".to_owned(),
+                sensors: HashMap::new(),
+                power_events: events,
+            };
+            PollResult::from([("node1".to_owned(), Either::left(host))])
+        };
+
+        // Flags already set on the first poll are not reported.
+        notify_power_events(&state, &poll(vec!["ac_failed"]));
+        notify_power_events(&state, &poll(vec![]));
+        notify_power_events(&state, &poll(vec!["power_on_by_fault"]));
+        notify_power_events(&state, &poll(vec!["power_on_by_fault"]));
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event["host"], "node1");
+        assert_eq!(event["events"], serde_json::json!(["power_on_by_fault"]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn summary_counts_only_unreachable_hosts_as_unreachable() {
+        let unreachable = ApiError {
+            kind: Some(ErrorKind::Unreachable),
+            ..ApiError::new(StatusCode::BAD_GATEWAY, "timed out")
+        };
+        let refused = ApiError {
+            kind: Some(ErrorKind::CommandError),
+            ..ApiError::new(StatusCode::BAD_GATEWAY, "insufficient privileges")
+        };
+        let hosts = PollResult::from([
+            ("node1".to_owned(), Either::right(unreachable)),
+            ("node2".to_owned(), Either::right(refused)),
+        ]);
+
+        let summary = summarize(hosts);
+        assert_eq!(summary.total, 2);
+        assert_eq!(summary.unreachable, 1);
+        let problems: Vec<_> = summary.problems.iter().map(|p| &*p.host).collect();
+        assert_eq!(problems, ["node1", "node2"]);
+    }
+
+    #[tokio::test]
+    async fn maintenance_survives_restart_and_failed_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("maintenance.json");
+        let maintenance = Maintenance::load(path.clone()).unwrap();
+        maintenance.set("node1", true).await.unwrap();
+        assert!(Maintenance::load(path.clone()).unwrap().contains("node1"));
+
+        // A flag which can't be saved must not take effect either.
+        std::fs::remove_dir_all(dir.path().join("state")).unwrap();
+        std::fs::write(dir.path().join("state"), "").unwrap();
+        assert!(maintenance.set("node2", true).await.is_err());
+        assert!(!maintenance.contains("node2"));
+        assert!(maintenance.contains("node1"));
     }
 }
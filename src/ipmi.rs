@@ -1,14 +1,24 @@
 // https://www.intel.com/content/dam/www/public/us/en/documents/product-briefs/ipmi-second-gen-interface-spec-v2-rev1-1.pdf
 // https://dl.dell.com/manuals/all-products/esuprt_ser_stor_net/esuprt_cloud_products/poweredge-c6100_reference%20guide_en-us.pdf
 
+use crate::config;
+
 use futures::TryFutureExt;
 use ipmi_rs::Ipmi;
+use ipmi_rs::connection::CompletionCode;
 use ipmi_rs::connection::IpmiCommand;
+use ipmi_rs::connection::IpmiError;
 use ipmi_rs::connection::Message;
 use ipmi_rs::connection::NetFn;
 use ipmi_rs::connection::NotEnoughData;
 use ipmi_rs::rmcp::Rmcp;
-use std::time::Duration;
+use ipmi_rs::sensor_event::{GetSensorReading, RawSensorReading, ThresholdReading};
+use ipmi_rs::storage::sdr::event_reading_type_code::EventReadingTypeCodes;
+use ipmi_rs::storage::sdr::{EntityInstance, Record, SensorRecordCommon};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, Debug)]
 #[allow(dead_code)]
@@ -79,7 +89,8 @@ impl ipmi_rs::connection::IpmiCommand for GetChassisStatus {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 #[allow(dead_code)]
 pub enum ChassisControl {
     PowerDown = 0,
@@ -103,31 +114,746 @@ impl IpmiCommand for ChassisControl {
     }
 }
 
-#[tracing::instrument(skip(username, password, f))]
+/// Set Session Privilege Level, see section 22.18 of the spec.
+pub struct SetSessionPrivilegeLevel(pub config::Privilege);
+
+impl From<SetSessionPrivilegeLevel> for Message {
+    fn from(command: SetSessionPrivilegeLevel) -> Message {
+        let level = match command.0 {
+            config::Privilege::User => 2,
+            config::Privilege::Operator => 3,
+            config::Privilege::Admin => 4,
+        };
+        Message::new_request(NetFn::App, 0x3B, vec![level])
+    }
+}
+
+impl IpmiCommand for SetSessionPrivilegeLevel {
+    type Output = ();
+    type Error = ();
+
+    fn parse_success_response(_data: &[u8]) -> Result<Self::Output, Self::Error> {
+        Ok(())
+    }
+}
+
+/// LAN configuration parameters, by parameter selector.
+#[derive(Copy, Clone, Debug)]
+pub enum LanParameter {
+    IpAddress = 3,
+    IpAddressSource = 4,
+    MacAddress = 5,
+    SubnetMask = 6,
+    DefaultGateway = 12,
+    VlanId = 20,
+}
+
+/// Get LAN Configuration Parameters, see section 23.2 of the spec. The
+/// output is the parameter data, without the revision byte.
+pub struct GetLanConfigParameter(pub LanParameter);
+
+impl From<GetLanConfigParameter> for Message {
+    fn from(command: GetLanConfigParameter) -> Message {
+        // Channel 0xE means whichever channel the request arrived on.
+        Message::new_request(NetFn::Transport, 0x02, vec![0x0E, command.0 as u8, 0, 0])
+    }
+}
+
+impl IpmiCommand for GetLanConfigParameter {
+    type Output = Vec<u8>;
+    type Error = NotEnoughData;
+
+    fn parse_success_response(data: &[u8]) -> Result<Self::Output, Self::Error> {
+        match data {
+            [_revision, data @ ..] if !data.is_empty() => Ok(data.to_vec()),
+            _ => Err(NotEnoughData),
+        }
+    }
+}
+
+fn describe_completion_code(code: CompletionCode) -> Option<&'static str> {
+    Some(match code {
+        CompletionCode::Success => return None,
+        CompletionCode::NodeBusy => "the BMC is busy",
+        CompletionCode::InvalidCommand => "command not supported",
+        CompletionCode::ProcessingTimeout => "the BMC timed out processing the command",
+        CompletionCode::OutOfSpace => "the BMC is out of space",
+        CompletionCode::RequestDataLengthInvalid | CompletionCode::RequestDataTruncated => {
+            "invalid request length"
+        }
+        CompletionCode::ParameterOutOfRange => "parameter out of range",
+        CompletionCode::RequestedDatapointNotPresent => {
+            "requested sensor, data or record not present"
+        }
+        CompletionCode::InvalidDataFieldInRequest => "invalid data field in request",
+        CompletionCode::CommandIllegalForSensorOrRecord => {
+            "command illegal for this sensor or record"
+        }
+        CompletionCode::SdrRepositoryInUpdateMode => "the SDR repository is being updated",
+        CompletionCode::DeviceInFirmwareUpdateMode => "the BMC is in firmware update mode",
+        CompletionCode::BmcInitializationInProgress => "the BMC is still initialising",
+        CompletionCode::DestinationUnavailable => "destination unavailable",
+        CompletionCode::CannotExecuteCommandInsufficientPrivileges => "insufficient privileges",
+        CompletionCode::CommandNotSupportedInPresentState => {
+            "command not supported in present state"
+        }
+        CompletionCode::SubFunctionDisabled => "command sub-function disabled or unavailable",
+        _ => return None,
+    })
+}
+
+/// Convert an error from `Ipmi::send_recv` into something an operator can
+/// make sense of, spelling out the completion code when the BMC rejected the
+/// command.
+pub fn ipmi_error<CON, P>(e: IpmiError<CON, P>) -> anyhow::Error
+where
+    CON: std::fmt::Debug,
+    P: std::fmt::Debug,
+{
+    match e {
+        IpmiError::Failed {
+            netfn,
+            cmd,
+            completion_code,
+            ..
+        }
+        | IpmiError::ParsingFailed {
+            netfn,
+            cmd,
+            completion_code,
+            ..
+        } if completion_code != CompletionCode::Success => {
+            match describe_completion_code(completion_code) {
+                Some(description) => anyhow::anyhow!(
+                    "{description} ({netfn:?} command {cmd:#04x}: {completion_code:?})"
+                ),
+                None => {
+                    anyhow::anyhow!("{netfn:?} command {cmd:#04x} failed with {completion_code:?}")
+                }
+            }
+        }
+        e => anyhow::anyhow!("{:?}", e),
+    }
+}
+
+/// A sensor reading or threshold, formatted with its unit for display, and
+/// converted for comparisons.
+#[derive(Debug, Clone)]
+pub struct SensorValue {
+    pub display: String,
+    pub converted: Option<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SensorReading {
+    pub name: String,
+    /// The component the sensor is attached to, such as `CPU1`, if the SDR
+    /// says.
+    pub entity: Option<String>,
+    pub reading: SensorValue,
+    pub lower_critical: Option<SensorValue>,
+    pub upper_critical: Option<SensorValue>,
+    /// Whether the BMC reports a critical or non-recoverable threshold as
+    /// crossed, if it reports that at all.
+    pub critical: Option<bool>,
+}
+
+/// The commands sent to BMCs. This is a trait so that the code making sense
+/// of their responses can be tested without one.
+pub trait Bmc {
+    fn chassis_status(&mut self) -> anyhow::Result<ChassisStatus>;
+    fn chassis_control(&mut self, control: ChassisControl) -> anyhow::Result<()>;
+    /// The data of a LAN configuration parameter, or `None` if the BMC
+    /// doesn't support it.
+    fn lan_parameter(&mut self, parameter: LanParameter) -> anyhow::Result<Option<Vec<u8>>>;
+    /// The threshold sensors whose name passes `filter`, and the discrete
+    /// sensors it selects. Sensors which can't be read are left out.
+    fn sensors(&mut self, filter: &config::SensorFilter) -> Vec<SensorReading>;
+}
+
+/// Notes whether a command changing the power state of the host was sent
+/// through `bmc`. Once one may have reached the BMC, whatever it was part of
+/// must not be retried, or a host could be power cycled twice.
+pub struct Tracked<'a> {
+    bmc: &'a mut dyn Bmc,
+    pub control_sent: bool,
+}
+
+impl<'a> Tracked<'a> {
+    pub fn new(bmc: &'a mut dyn Bmc) -> Tracked<'a> {
+        Tracked {
+            bmc,
+            control_sent: false,
+        }
+    }
+}
+
+impl Bmc for Tracked<'_> {
+    fn chassis_status(&mut self) -> anyhow::Result<ChassisStatus> {
+        self.bmc.chassis_status()
+    }
+
+    fn chassis_control(&mut self, control: ChassisControl) -> anyhow::Result<()> {
+        // Set before sending: a lost reply doesn't mean it wasn't carried out.
+        self.control_sent = true;
+        self.bmc.chassis_control(control)
+    }
+
+    fn lan_parameter(&mut self, parameter: LanParameter) -> anyhow::Result<Option<Vec<u8>>> {
+        self.bmc.lan_parameter(parameter)
+    }
+
+    fn sensors(&mut self, filter: &config::SensorFilter) -> Vec<SensorReading> {
+        self.bmc.sensors(filter)
+    }
+}
+
+/// A name for the entity a sensor record is attached to, made of a short
+/// name for its entity ID (IPMI table 43-13) and its instance number.
+fn entity_name(entity_id: u8, instance_number: u8) -> Option<String> {
+    let name = match entity_id {
+        // Unspecified.
+        0x00 => return None,
+        0x03 => "CPU",
+        0x07 => "Board",
+        0x0a => "PSU",
+        0x1d => "Fan",
+        0x20 => "DIMM",
+        id => return Some(format!("entity{id}.{instance_number}")),
+    };
+    Some(format!("{name}{instance_number}"))
+}
+
+/// Enumerate the SDR repository. Some BMCs don't have one, or return garbage
+/// that makes the iterator panic, so failures here only cost us the sensors
+/// rather than the whole host.
+fn read_sdrs(ipmi: &mut Ipmi<Rmcp>) -> Vec<Record> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        ipmi.sdrs().collect::<Vec<_>>()
+    })) {
+        Ok(sensors) => {
+            if sensors.is_empty() {
+                tracing::warn!("BMC returned no SDR records");
+            }
+            sensors
+        }
+        Err(_) => {
+            tracing::warn!("failed to enumerate the SDR repository");
+            Vec::new()
+        }
+    }
+}
+
+/// Hosts with fewer selected sensors than this per session read them all over
+/// one, since opening another costs about as many round trips as it saves.
+const MIN_SENSORS_PER_SESSION: usize = 8;
+
+/// A session with a BMC, along with what is needed to open more to it.
+struct Session<'a> {
+    ipmi: Ipmi<Rmcp>,
+    pool: &'a SessionPool,
+    address: &'a str,
+    relay: Option<SocketAddr>,
+    config: &'a config::Ipmi,
+}
+
+impl Bmc for Session<'_> {
+    fn chassis_status(&mut self) -> anyhow::Result<ChassisStatus> {
+        self.ipmi.send_recv(GetChassisStatus).map_err(ipmi_error)
+    }
+
+    fn chassis_control(&mut self, control: ChassisControl) -> anyhow::Result<()> {
+        self.ipmi.send_recv(control).map_err(ipmi_error)
+    }
+
+    fn lan_parameter(&mut self, parameter: LanParameter) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.ipmi.send_recv(GetLanConfigParameter(parameter)) {
+            Ok(data) => Ok(Some(data)),
+            // Completion code 0x80 means the parameter isn't supported.
+            Err(IpmiError::Failed {
+                completion_code: CompletionCode::CommandCode(0x80),
+                ..
+            }) => Ok(None),
+            Err(e) => Err(ipmi_error(e)),
+        }
+    }
+
+    /// Every reading is a round trip to the BMC, and `ipmi_rs` only has one
+    /// command in flight per session, so the sensors are split across up to
+    /// `sensor_sessions` sessions read from in parallel.
+    fn sensors(&mut self, filter: &config::SensorFilter) -> Vec<SensorReading> {
+        let start = Instant::now();
+        let records = read_sdrs(&mut self.ipmi);
+        let selected: Vec<&Record> = records.iter().filter(|s| is_selected(s, filter)).collect();
+        let sessions = self
+            .config
+            .sensor_sessions
+            .min(selected.len() / MIN_SENSORS_PER_SESSION)
+            .max(1);
+
+        let (pool, address, relay, config) = (self.pool, self.address, self.relay, self.config);
+        let ipmi = &mut self.ipmi;
+        let mut chunks = selected.chunks(selected.len().div_ceil(sessions).max(1));
+        let first = chunks.next().unwrap_or_default();
+        let span = tracing::Span::current();
+        let sensors = std::thread::scope(|scope| {
+            let workers: Vec<_> = chunks
+                .map(|records| {
+                    let span = &span;
+                    scope.spawn(move || {
+                        let _enter = span.enter();
+                        let mut ipmi = match pool.open(address, relay, config) {
+                            Ok(ipmi) => ipmi,
+                            Err(e) => {
+                                tracing::warn!(
+                                    "cannot open another session to read sensors: {e:#}"
+                                );
+                                return Err(records);
+                            }
+                        };
+                        let (sensors, ok) = read_sensors(&mut ipmi, records);
+                        if ok {
+                            pool.put(address, ipmi, config.sensor_sessions);
+                        }
+                        Ok(sensors)
+                    })
+                })
+                .collect();
+
+            let (mut sensors, _) = read_sensors(ipmi, first);
+            for worker in workers {
+                match worker.join().expect("sensor reader panicked") {
+                    Ok(read) => sensors.extend(read),
+                    // Fall back to the session we have.
+                    Err(records) => sensors.extend(read_sensors(ipmi, records).0),
+                }
+            }
+            sensors
+        });
+
+        tracing::debug!(
+            sensors = selected.len(),
+            sessions,
+            elapsed_ms = start.elapsed().as_millis(),
+            "read sensors"
+        );
+        sensors
+    }
+}
+
+/// Whether `filter` selects the sensor described by `s`.
+fn is_selected(s: &Record, filter: &config::SensorFilter) -> bool {
+    let Some(common) = s.common_data() else {
+        return false;
+    };
+    let Some(name) = s.id().map(|id| id.to_string()) else {
+        return false;
+    };
+    let discrete = common.event_reading_type_code != EventReadingTypeCodes::Threshold;
+    filter.matches(&name) && (!discrete || filter.matches_discrete(&name))
+}
+
+/// Read the sensors described by `records`, leaving out those which can't be
+/// read. Also returns whether every command succeeded, as the session may
+/// have died otherwise, and shouldn't be reused.
+fn read_sensors(ipmi: &mut Ipmi<Rmcp>, records: &[&Record]) -> (Vec<SensorReading>, bool) {
+    let mut ok = true;
+    let sensors = records
+        .iter()
+        .filter_map(|s| {
+            read_sensor(ipmi, s).unwrap_or_else(|e| {
+                tracing::debug!(sensor = ?s.id(), "cannot read sensor: {e:#}");
+                ok = false;
+                None
+            })
+        })
+        .collect();
+    (sensors, ok)
+}
+
+/// Read the sensor described by `s`, if it has a reading we can make sense
+/// of. Only failing to talk to the BMC is an error.
+fn read_sensor(ipmi: &mut Ipmi<Rmcp>, s: &Record) -> anyhow::Result<Option<SensorReading>> {
+    let (Some(common), Some(id)) = (s.common_data(), s.id()) else {
+        return Ok(None);
+    };
+    let cmd = GetSensorReading::for_sensor_key(&common.key);
+    let raw = ipmi.send_recv(cmd).map_err(ipmi_error)?;
+    Ok(sensor_reading(s, common, id.to_string(), &raw))
+}
+
+/// Make sense of the reading `raw` of the sensor described by `s`.
+fn sensor_reading(
+    s: &Record,
+    common: &SensorRecordCommon,
+    name: String,
+    raw: &RawSensorReading,
+) -> Option<SensorReading> {
+    let (EntityInstance::Physical {
+        instance_number, ..
+    }
+    | EntityInstance::LogicalContainer {
+        instance_number, ..
+    }) = common.entity_instance;
+    let entity = entity_name(common.entity_id, instance_number);
+    if common.event_reading_type_code != EventReadingTypeCodes::Threshold {
+        // The reading byte is meaningless for these: what they report is
+        // which of their states are asserted.
+        let states = discrete_states(raw.offset_data_1?, raw.offset_data_2);
+        return Some(SensorReading {
+            name,
+            entity,
+            reading: SensorValue {
+                display: format!("{states:#06x}"),
+                converted: Some(f32::from(states)),
+            },
+            lower_critical: None,
+            upper_critical: None,
+            critical: None,
+        });
+    }
+
+    let reading = ThresholdReading::from(raw);
+    let critical = reading.threshold_status.map(|status| {
+        status.at_or_above_non_recoverable
+            || status.at_or_above_upper_critical
+            || status.at_or_below_lower_non_recoverable
+            || status.at_or_below_lower_critical
+    });
+    let Some(full) = s.full_sensor() else {
+        // Compact records have neither conversion factors nor thresholds,
+        // so the raw reading is the best we can do.
+        let raw = reading.reading?;
+        return Some(SensorReading {
+            name,
+            entity,
+            reading: SensorValue {
+                display: raw.to_string(),
+                converted: None,
+            },
+            lower_critical: None,
+            upper_critical: None,
+            critical,
+        });
+    };
+
+    let value = |raw: u8| {
+        Some(SensorValue {
+            display: full.display_reading(raw)?,
+            converted: full.convert(raw),
+        })
+    };
+    let thresholds = &full.thresholds;
+    Some(SensorReading {
+        name,
+        entity,
+        reading: value(reading.reading?)?,
+        lower_critical: thresholds.lower_critical.and_then(value),
+        upper_critical: thresholds.upper_critical.and_then(value),
+        critical,
+    })
+}
+
+/// The states asserted by a discrete sensor, as a bit mask of state offsets
+/// 0 to 14, from the last two bytes of its Get Sensor Reading response.
+fn discrete_states(offset_data_1: u8, offset_data_2: Option<u8>) -> u16 {
+    // The top bit of the second byte is reserved.
+    u16::from(offset_data_2.unwrap_or(0) & 0x7f) << 8 | u16::from(offset_data_1)
+}
+
+/// A failure to talk to a BMC. A failure on a pooled session says little,
+/// as the BMC may have dropped it, so it is only classified once the command
+/// has been retried on a fresh session.
+#[derive(Debug)]
+pub enum BmcError {
+    /// No session could be established: the BMC is down, unreachable, or
+    /// refused our credentials.
+    Unreachable(anyhow::Error),
+    /// A command failed on a freshly established session.
+    Command(anyhow::Error),
+}
+
+struct IdleSession {
+    ipmi: Ipmi<Rmcp>,
+    since: Instant,
+}
+
+/// Authenticated RMCP+ sessions kept alive between requests, keyed by BMC
+/// address. The handshake costs several round trips, which adds up when
+/// polling every host.
+///
+/// At most `sensor_sessions` idle sessions are kept per BMC, since they only
+/// have a handful of session slots. A session is dropped whenever a command
+/// fails on it, so the next request authenticates from scratch.
+pub struct SessionPool {
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<String, Vec<IdleSession>>>,
+}
+
+impl SessionPool {
+    pub fn new(idle_timeout: Duration) -> SessionPool {
+        SessionPool {
+            idle_timeout,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn take(&self, address: &str) -> Option<Ipmi<Rmcp>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let idle = sessions.get_mut(address)?;
+        idle.retain(|s| s.since.elapsed() < self.idle_timeout);
+        idle.pop().map(|s| s.ipmi)
+    }
+
+    /// An idle session to the BMC at `address`, or a new one.
+    fn open(
+        &self,
+        address: &str,
+        relay: Option<SocketAddr>,
+        config: &config::Ipmi,
+    ) -> anyhow::Result<Ipmi<Rmcp>> {
+        match self.take(address) {
+            Some(ipmi) => Ok(ipmi),
+            None => connect(address, relay, config),
+        }
+    }
+
+    /// Run `f` on a session to the BMC at `address`, and keep the session if
+    /// it succeeds. A pooled session may have died with its BMC, so if `f`
+    /// fails on one it is retried once on a session from `connect`, unless
+    /// `f` says it sent a power command first. Errors are classified after
+    /// that: failing to connect means the BMC is unreachable, while failing
+    /// on a fresh session, or after a power command, is the command's fault.
+    pub fn run<T, E>(
+        &self,
+        address: &str,
+        limit: usize,
+        connect: impl Fn() -> anyhow::Result<Ipmi<Rmcp>>,
+        mut f: impl FnMut(Ipmi<Rmcp>) -> (Result<T, E>, Ipmi<Rmcp>, bool),
+    ) -> Result<T, BmcError>
+    where
+        E: Into<anyhow::Error>,
+    {
+        if let Some(ipmi) = self.take(address) {
+            match f(ipmi) {
+                (Ok(result), ipmi, _) => {
+                    self.put(address, ipmi, limit);
+                    return Ok(result);
+                }
+                // The command may have been carried out even though its
+                // reply, or the read-back after it, was lost.
+                (Err(e), _, true) => return Err(BmcError::Command(e.into())),
+                (Err(e), _, false) => {
+                    let e = e.into();
+                    tracing::debug!(?e, "pooled session failed, reconnecting");
+                }
+            }
+        }
+
+        let ipmi = connect().map_err(BmcError::Unreachable)?;
+        let (result, ipmi, _) = f(ipmi);
+        let result = result.map_err(|e| BmcError::Command(e.into()))?;
+        self.put(address, ipmi, limit);
+        Ok(result)
+    }
+
+    /// Keep `ipmi` for reuse, unless `limit` sessions to its BMC already are.
+    fn put(&self, address: &str, ipmi: Ipmi<Rmcp>, limit: usize) {
+        if self.idle_timeout.is_zero() {
+            return;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, idle| {
+            idle.retain(|s| s.since.elapsed() < self.idle_timeout);
+            !idle.is_empty()
+        });
+        let idle = sessions.entry(address.to_owned()).or_default();
+        if idle.len() < limit.max(1) {
+            idle.push(IdleSession {
+                ipmi,
+                since: Instant::now(),
+            });
+        }
+    }
+}
+
+fn connect(
+    address: &str,
+    relay: Option<SocketAddr>,
+    config: &config::Ipmi,
+) -> anyhow::Result<Ipmi<Rmcp>> {
+    let timeout = Duration::from_secs(1);
+    let mut rmcp = match (relay, config.static_addresses.get(address)) {
+        (Some(relay), _) => Rmcp::new(relay, timeout)?,
+        (None, Some(&ip)) => Rmcp::new(SocketAddr::new(ip, 623), timeout)?,
+        (None, None) => Rmcp::new((address, 623), timeout)?,
+    };
+    let password = config.password.as_ref().unwrap().as_bytes();
+    let privilege = config.privilege;
+    // Activation is where a BMC refuses a user it doesn't grant any of the
+    // requested privileges to, so say what was asked of which BMC. The
+    // username is left out, like the rest of the credentials.
+    rmcp.activate(true, Some(&config.username), Some(password))
+        .map_err(|e| {
+            anyhow::anyhow!("cannot open a {privilege:?} session to the BMC at {address}: {e:?}")
+        })?;
+
+    let mut ipmi = Ipmi::new(rmcp);
+    match ipmi.send_recv(SetSessionPrivilegeLevel(privilege)) {
+        Ok(()) => Ok(ipmi),
+        // 0x80 and 0x81 mean the level isn't available to this user or channel.
+        Err(IpmiError::Failed {
+            completion_code:
+                CompletionCode::CommandCode(0x80 | 0x81)
+                | CompletionCode::CannotExecuteCommandInsufficientPrivileges,
+            ..
+        }) => {
+            tracing::warn!(?privilege, "BMC refused the requested privilege level");
+            anyhow::bail!(
+                "the BMC at {address} does not grant {privilege:?} privileges to the configured user"
+            )
+        }
+        Err(e) => Err(ipmi_error(e)),
+    }
+}
+
+#[tracing::instrument(skip_all, fields(address = host.address))]
 pub fn ipmi_do<F, T, E>(
-    hostname: &str,
-    username: &str,
-    password: &[u8],
-    f: F,
-) -> impl Future<Output = anyhow::Result<T>> + use<F, T, E>
+    pool: &Arc<SessionPool>,
+    host: &config::Host,
+    config: &config::Ipmi,
+    mut f: F,
+) -> impl Future<Output = Result<T, BmcError>> + use<F, T, E>
 where
-    F: FnOnce(&mut Ipmi<Rmcp>) -> Result<T, E> + Send + 'static,
+    F: FnMut(&mut dyn Bmc) -> Result<T, E> + Send + 'static,
     T: Send + 'static,
     E: Into<anyhow::Error> + Send + Sync,
 {
-    let hostname = hostname.to_owned();
-    let username = username.to_owned();
-    let password = password.to_owned();
+    let pool = pool.clone();
+    let address = host.address.clone();
+    let relay = host.relay;
+    let config = config.clone();
     let span = tracing::Span::current();
     tokio::task::spawn_blocking(move || {
         let _enter = span.enter();
-        let mut rmcp = Rmcp::new((hostname.as_ref(), 623), Duration::from_secs(1)).unwrap();
-        rmcp.activate(true, Some(&username), Some(&password))
-            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
-
-        let mut ipmi = Ipmi::new(rmcp);
-        let result = f(&mut ipmi).map_err(Into::into)?;
-        Ok(result)
+        pool.run(
+            &address,
+            config.sensor_sessions,
+            || connect(&address, relay, &config),
+            |ipmi| {
+                let mut session = Session {
+                    ipmi,
+                    pool: &pool,
+                    address: &address,
+                    relay,
+                    config: &config,
+                };
+                let mut tracked = Tracked::new(&mut session);
+                let result = f(&mut tracked);
+                let control_sent = tracked.control_sent;
+                (result, session.ipmi, control_sent)
+            },
+        )
     })
     .unwrap_or_else(|e: tokio::task::JoinError| panic!("ipmi command panicked: {:?}", e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BmcError, SessionPool, discrete_states, entity_name};
+    use ipmi_rs::Ipmi;
+    use ipmi_rs::rmcp::Rmcp;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    const BMC: &str = "10.0.0.1";
+
+    /// A session that is never used to talk to anything.
+    fn session() -> Ipmi<Rmcp> {
+        Ipmi::new(Rmcp::new(("127.0.0.1", 9), Duration::from_secs(1)).unwrap())
+    }
+
+    #[test]
+    fn stale_session_is_retried() {
+        let pool = SessionPool::new(Duration::from_secs(60));
+        pool.put(BMC, session(), 1);
+
+        let connects = Cell::new(0);
+        let attempts = Cell::new(0);
+        let result = pool.run(
+            BMC,
+            1,
+            || {
+                connects.set(connects.get() + 1);
+                Ok(session())
+            },
+            |ipmi| {
+                attempts.set(attempts.get() + 1);
+                let result = match attempts.get() {
+                    1 => Err(anyhow::anyhow!("timed out")),
+                    _ => Ok(()),
+                };
+                (result, ipmi, false)
+            },
+        );
+        assert!(result.is_ok());
+        assert_eq!((connects.get(), attempts.get()), (1, 2));
+        // The fresh session is kept in place of the stale one.
+        assert!(pool.take(BMC).is_some());
+        assert!(pool.take(BMC).is_none());
+    }
+
+    /// A BMC which has gone down fails its pooled session, then can't be
+    /// reconnected to, which makes it unreachable rather than a bad command.
+    #[test]
+    fn unreachable_after_pooled_session_fails() {
+        let pool = SessionPool::new(Duration::from_secs(60));
+        pool.put(BMC, session(), 1);
+
+        let result = pool.run(
+            BMC,
+            1,
+            || Err(anyhow::anyhow!("no response to the RMCP+ handshake")),
+            |ipmi| (Err::<(), _>(anyhow::anyhow!("timed out")), ipmi, false),
+        );
+        assert!(matches!(result, Err(BmcError::Unreachable(_))));
+    }
+
+    #[test]
+    fn command_error_on_fresh_session() {
+        let pool = SessionPool::new(Duration::from_secs(60));
+
+        let result = pool.run(
+            BMC,
+            1,
+            || Ok(session()),
+            |ipmi| {
+                (
+                    Err::<(), _>(anyhow::anyhow!("invalid command")),
+                    ipmi,
+                    false,
+                )
+            },
+        );
+        assert!(matches!(result, Err(BmcError::Command(_))));
+        // Failed sessions aren't kept.
+        assert!(pool.take(BMC).is_none());
+    }
+
+    #[test]
+    fn entity_names() {
+        assert_eq!(entity_name(0x03, 1).as_deref(), Some("CPU1"));
+        assert_eq!(entity_name(0x20, 12).as_deref(), Some("DIMM12"));
+        assert_eq!(entity_name(0x42, 2).as_deref(), Some("entity66.2"));
+        assert_eq!(entity_name(0x00, 1), None);
+    }
+
+    #[test]
+    fn discrete_reading_is_asserted_states() {
+        // States 0 and 7 from the first byte, state 8 from the second.
+        assert_eq!(discrete_states(0x81, Some(0x01)), 0x0181);
+        // The reserved top bit is ignored, and the second byte is optional.
+        assert_eq!(discrete_states(0x02, Some(0x80)), 0x0002);
+        assert_eq!(discrete_states(0x02, None), 0x0002);
+    }
+}
@@ -1,22 +1,44 @@
+mod audit;
 mod binary_cache;
+mod blocking;
 mod config;
+mod error;
 mod hosts;
 mod ipmi;
+mod metrics;
 mod nar;
 mod pxe;
+mod query;
+mod rate_limit;
+mod reload;
+mod request_id;
 mod store;
+mod store_path;
+mod tls;
 
-use axum::Router;
-use axum::routing::{get, put};
+use arc_swap::ArcSwap;
+use axum::extract::{Request, State};
+use axum::middleware::from_fn;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
 use axum_extra::middleware::option_layer;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use http::{StatusCode, Uri};
+use serde::Serialize;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tower::ServiceExt as _;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 use tracing::Level;
 use tracing_subscriber::EnvFilter;
 
-use crate::config::Config;
-use crate::hosts::{ipmi_host_get_handler, ipmi_host_put_handler, ipmi_hosts_handler};
+use crate::config::{Config, SharedConfig};
+use crate::error::ApiError;
 
 #[derive(rust_embed::RustEmbed, Clone)]
 #[folder = "web/dist"]
@@ -28,13 +50,74 @@ use tower_http::cors::{self, CorsLayer};
 #[command(version, about, long_about = None)]
 struct Cli {
     #[arg(short, long, value_name = "FILE")]
-    config: PathBuf,
+    config: Option<PathBuf>,
 
     #[arg(short, long, env = "PORT", default_value_t = 8080)]
     port: u16,
 
     #[arg(long)]
     cors_allow_all: bool,
+
+    /// Serve everything under this prefix, e.g. `/datacenter`, for use behind
+    /// a reverse proxy. Overrides `base_path` from the config.
+    #[arg(long, value_name = "PATH")]
+    base_path: Option<String>,
+
+    /// Don't serve the web UI, and answer unknown routes with a JSON 404.
+    #[arg(long)]
+    no_ui: bool,
+
+    /// Print the effective configuration, with secrets redacted, and exit.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Reload the configuration whenever its files change. Hosts, IPMI and
+    /// boot settings take effect immediately, while listening, the base path,
+    /// TLS, the store and the binary caches still need a restart.
+    #[arg(long, requires = "config")]
+    watch_config: bool,
+
+    /// Serve only what is already in the store, answering 503 for anything
+    /// else rather than downloading it. Overrides `pxe.read_only_store`.
+    #[arg(long)]
+    read_only_store: bool,
+
+    /// PEM certificate chain to serve HTTPS with.
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key matching `--tls-cert`.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the HTTP server (the default).
+    Serve,
+
+    /// Resolve and download a host's boot files, then exit.
+    Selftest {
+        /// Name of the host to boot, as it appears in the config.
+        hostname: String,
+    },
+
+    /// Add an already extracted store path to the store, moving it there.
+    Import {
+        /// Hash part of the store path.
+        hash: String,
+        /// Directory holding the store path's contents.
+        path: PathBuf,
+    },
+
+    /// Write a NAR serialisation of a path to stdout.
+    Pack {
+        /// File or directory to pack.
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -45,40 +128,217 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let args = Cli::parse();
+    let config = || match &args.config {
+        Some(path) => Config::load(path),
+        None => anyhow::bail!("the --config option is required"),
+    };
 
-    let config = std::fs::read(args.config)?;
-    let mut config: Config = toml::from_slice(&config)?;
+    if args.print_config {
+        print!("{}", toml::to_string(&config()?)?);
+        return Ok(());
+    }
+
+    match args.command {
+        None | Some(Command::Serve) => serve(config()?, &args).await,
+        Some(Command::Selftest { ref hostname }) => pxe::selftest(config()?, hostname).await,
+        Some(Command::Import { ref hash, ref path }) => pxe::import(config()?, hash, path).await,
+        Some(Command::Pack { ref path }) => nar::Writer::new(tokio::io::stdout()).pack(path).await,
+    }
+}
 
-    match (&config.ipmi.password, &config.ipmi.password_file) {
-        (Some(_), None) => (),
-        (None, Some(path)) => {
-            let password = std::fs::read_to_string(path)?;
-            config.ipmi.password = password.trim_end_matches('\n').to_owned().into();
+async fn not_found_handler() -> ApiError {
+    ApiError::new(StatusCode::NOT_FOUND, "not found")
+}
+
+/// Paths belonging to the API, which must never be answered with the UI.
+const API_PREFIXES: &[&str] = &["/host", "/hosts", "/pxe", "/config", "/metrics", "/version"];
+
+/// Whether `path` is one of `API_PREFIXES` or below one, going by whole
+/// segments so that eg. `/pxe-ui` is left to the UI.
+fn is_api_path(path: &str) -> bool {
+    API_PREFIXES.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+async fn ui_fallback_handler(request: Request) -> Response {
+    if is_api_path(request.uri().path()) {
+        return not_found_handler().await.into_response();
+    }
+
+    axum_embed::ServeEmbed::<Assets>::new()
+        .oneshot(request)
+        .await
+        .into_response()
+}
+
+/// The loaded configuration, with credentials redacted. Like the rest of the
+/// read-only API, it is served to anyone who can reach it unless a client
+/// CA is configured.
+async fn config_handler(State(config): State<SharedConfig>) -> Json<Config> {
+    Json(Config::clone(&config.load()))
+}
+
+#[derive(Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    commit: Option<&'static str>,
+    build_time: String,
+}
+
+/// Which build is running, for deploy tooling to check against.
+async fn version_handler() -> Json<BuildInfo> {
+    let commit = env!("GIT_COMMIT");
+    let timestamp: i64 = env!("BUILD_TIMESTAMP").parse().unwrap();
+    Json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: (!commit.is_empty()).then_some(commit),
+        build_time: OffsetDateTime::from_unix_timestamp(timestamp)
+            .unwrap()
+            .format(&Rfc3339)
+            .unwrap(),
+    })
+}
+
+async fn serve(mut config: Config, args: &Cli) -> anyhow::Result<()> {
+    let base_path_override = args.base_path.clone();
+    let read_only_store = args.read_only_store;
+    let prepare = move |config: &mut Config| {
+        if let Some(base_path) = &base_path_override {
+            config.base_path = Some(base_path.clone());
+        }
+        if read_only_store {
+            config.pxe.read_only_store = true;
         }
-        (None, None) => anyhow::bail!("Either `password` or `password_file` must be provided"),
-        (Some(_), Some(_)) => anyhow::bail!("Cannot set both `password` and `password_file`"),
+    };
+    prepare(&mut config);
+    let base_path = config.base_path().to_owned();
+    if !base_path.is_empty() && !base_path.starts_with('/') {
+        anyhow::bail!("the base path must start with a '/'");
     }
 
-    let serve_assets = axum_embed::ServeEmbed::<Assets>::new();
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(config::Tls {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: config.tls.as_ref().and_then(|t| t.client_ca.clone()),
+        }),
+        _ => config.tls.clone(),
+    };
+    let require_client_cert = tls.as_ref().is_some_and(|t| t.client_ca.is_some());
+
+    // Cache URLs may carry credentials.
+    let caches: Vec<_> = config
+        .pxe
+        .caches
+        .iter()
+        .map(|url| config::redact_userinfo(url).to_string())
+        .collect();
+    tracing::info!(
+        hosts = config.host.len(),
+        ?caches,
+        cachix = ?config.pxe.cachix,
+        store = %config.pxe.store.display(),
+        read_only_store = config.pxe.read_only_store,
+        base_path,
+        tls = tls.is_some(),
+        client_cert = require_client_cert,
+        cors_allow_all = args.cors_allow_all,
+        ui = !args.no_ui,
+        audit_log = config.audit.is_some(),
+        "starting"
+    );
+
+    let audit_log = Arc::new(audit::AuditLog::open(config.audit.as_ref())?);
+    let config: SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+    if args.watch_config
+        && let Some(path) = &args.config
+    {
+        reload::watch(path.clone(), config.clone(), prepare)?;
+    }
+    let client_cert_layer =
+        || option_layer(require_client_cert.then(|| from_fn(tls::require_client_certificate)));
+
     let app = Router::new()
-        .route("/hosts", get(ipmi_hosts_handler))
-        .route("/host/{hostname}", get(ipmi_host_get_handler))
-        .route("/host/{hostname}/command", put(ipmi_host_put_handler))
-        .nest("/pxe", pxe::router(config.clone()))
-        .fallback_service(serve_assets)
+        .merge(hosts::router(
+            config.clone(),
+            require_client_cert,
+            audit_log,
+        )?)
+        .route("/metrics", get(metrics::handler))
+        .route("/version", get(version_handler))
+        .route(
+            "/config",
+            get(config_handler).route_layer(client_cert_layer()),
+        )
+        .nest("/pxe", pxe::router(config.clone(), require_client_cert)?);
+
+    let app = if args.no_ui {
+        app.fallback(not_found_handler)
+    } else {
+        app.fallback(ui_fallback_handler)
+    };
+
+    let app = app
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
+        .layer(from_fn(request_id::scope))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .layer(option_layer(
             args.cors_allow_all
                 .then(|| CorsLayer::new().allow_origin(cors::Any)),
         ))
         .with_state(config);
+    let app = if base_path.is_empty() {
+        app
+    } else {
+        // `nest` matches `/base` and `/base/...` but not `/base/` itself,
+        // which is where the UI lives.
+        let index = app.clone().map_request(|mut request: Request| {
+            *request.uri_mut() = Uri::from_static("/");
+            request
+        });
+        Router::new()
+            .route_service(&format!("{base_path}/"), index)
+            .nest(&base_path, app)
+    };
 
-    let listener = tokio::net::TcpListener::bind(("0.0.0.0", args.port)).await?;
-    tracing::info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await?;
+    if let Some(tls) = tls {
+        let tls_config = tls::server_config(&tls).await?;
+        let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
+        tracing::info!("listening on {addr} (TLS)");
+        axum_server::bind(addr)
+            .acceptor(tls::ClientCertificateAcceptor::new(tls_config))
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+    } else {
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", args.port)).await?;
+        tracing::info!("listening on {}", listener.local_addr().unwrap());
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::is_api_path;
+
+    #[test]
+    fn api_paths_match_whole_segments() {
+        for path in ["/hosts", "/host/node1", "/pxe", "/pxe/file/x", "/version"] {
+            assert!(is_api_path(path), "{path}");
+        }
+        for path in ["/", "/hostsfoo", "/pxe-ui", "/configure", "/assets/app.js"] {
+            assert!(!is_api_path(path), "{path}");
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use axum::response::IntoResponse;
+use http::header::CONTENT_TYPE;
+use prometheus::{
+    Counter, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// Time taken to answer a boot request, from pin resolution to any downloads.
+pub static BOOT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "pxe_boot_duration_seconds",
+                "Time taken to answer a PXE boot request",
+            )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60., 120.]),
+            &["hostname"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Store paths requested by the PXE handlers, by whether they were already present.
+pub static STORE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "pxe_store_lookups_total",
+                "Store paths requested, by whether they had to be downloaded",
+            ),
+            &["result"],
+        )
+        .unwrap(),
+    )
+});
+
+/// NARs downloaded, by the host they were needed for, which is empty for
+/// files fetched through a signed URL.
+pub static NAR_DOWNLOADS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "pxe_nar_downloads_total",
+                "NARs downloaded, by the host which needed them",
+            ),
+            &["hostname"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Time taken to extract a NAR into the store. The NAR is streamed, so this
+/// includes downloading and decompressing it.
+pub static NAR_EXTRACT_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "pxe_nar_extract_duration_seconds",
+                "Time taken to download and extract a NAR into the store",
+            )
+            .buckets(vec![0.1, 0.5, 1., 2.5, 5., 10., 30., 60., 120., 300., 600.]),
+        )
+        .unwrap(),
+    )
+});
+
+/// The part of `NAR_EXTRACT_DURATION` spent waiting for the NAR to be
+/// downloaded and decompressed, rather than writing it to disk.
+pub static NAR_EXTRACT_INPUT_WAIT: LazyLock<Counter> = LazyLock::new(|| {
+    register(
+        Counter::new(
+            "pxe_nar_extract_input_wait_seconds_total",
+            "Time NAR extraction spent waiting for the download and decompression",
+        )
+        .unwrap(),
+    )
+});
+
+/// Entries extracted from NARs, by kind.
+pub static NAR_EXTRACTED_ENTRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "pxe_nar_extracted_entries_total",
+                "Files, directories and symbolic links extracted from NARs",
+            ),
+            &["kind"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static NAR_EXTRACTED_BYTES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "pxe_nar_extracted_bytes_total",
+            "Bytes of regular files extracted from NARs",
+        )
+        .unwrap(),
+    )
+});
+
+/// Time taken to poll every host's BMC, whether for a request or in the
+/// background.
+pub static HOSTS_POLL_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register(
+        Histogram::with_opts(
+            HistogramOpts::new(
+                "hosts_poll_duration_seconds",
+                "Time taken to poll every host's BMC",
+            )
+            .buckets(vec![0.5, 1., 2.5, 5., 10., 30., 60., 120.]),
+        )
+        .unwrap(),
+    )
+});
+
+/// Hosts which failed to be polled, by whether their BMC could be reached.
+/// Hosts skipped while backing off aren't counted again.
+pub static HOSTS_POLL_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "hosts_poll_failures_total",
+                "Hosts which failed to be polled",
+            ),
+            &["kind"],
+        )
+        .unwrap(),
+    )
+});
+
+pub async fn handler() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("cannot encode metrics");
+    ([(CONTENT_TYPE, encoder.format_type().to_owned())], buffer)
+}
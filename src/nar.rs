@@ -1,20 +1,33 @@
 use anyhow::Context as _;
 use anyhow::bail;
 use camino::{Utf8Path, Utf8PathBuf};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::pin::Pin;
 use std::task::{Poll, ready};
+use std::time::{Duration, Instant};
 use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 pub struct Teller<R> {
     inner: R,
     position: u64,
+    /// When the current read started waiting for `inner`, if it is.
+    waiting_since: Option<Instant>,
+    /// How long reads have spent waiting for `inner` in total.
+    waited: Duration,
 }
 
 impl<R> Teller<R> {
     fn new(inner: R) -> Teller<R> {
-        Teller { inner, position: 0 }
+        Teller {
+            inner,
+            position: 0,
+            waiting_since: None,
+            waited: Duration::ZERO,
+        }
     }
 }
 
@@ -36,7 +49,16 @@ impl<R: tokio::io::AsyncRead + Unpin> AsyncRead for Teller<R> {
         let before = buf.filled().len();
 
         let inner = Pin::new(&mut self.inner);
-        ready!(inner.poll_read(cx, buf))?;
+        let result = inner.poll_read(cx, buf);
+        match (&result, self.waiting_since) {
+            (Poll::Pending, None) => self.waiting_since = Some(Instant::now()),
+            (Poll::Ready(_), Some(since)) => {
+                self.waited += since.elapsed();
+                self.waiting_since = None;
+            }
+            _ => (),
+        }
+        ready!(result)?;
 
         let after = buf.filled().len();
         self.position += (after - before) as u64;
@@ -69,9 +91,30 @@ enum State {
     Directory { context: Context },
 }
 
+/// How many entries a NAR may have by default. Even the largest store paths
+/// have a few hundred thousand, so this only stops archives built to exhaust
+/// the disk's inodes or the extraction loop.
+pub const DEFAULT_MAX_ENTRIES: u64 = 10_000_000;
+
 pub struct Reader<R> {
     inner: Teller<Pin<Box<R>>>,
     state: Option<State>,
+    /// Entries read so far, counting the root.
+    entries: u64,
+    max_entries: u64,
+}
+
+/// What `Reader::extract` wrote out.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractStats {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// The total size of the regular files.
+    pub bytes: u64,
+    /// Time spent waiting for the NAR to arrive, as opposed to writing it
+    /// out, which is most of the time a slow download or decompression takes.
+    pub input_wait: Duration,
 }
 
 #[derive(derive_more::Debug)]
@@ -94,6 +137,10 @@ impl Context {
         }
     }
 
+    fn depth(&self) -> usize {
+        self.0.as_ref().map_or(0, |path| path.components().count())
+    }
+
     fn pop(&mut self) -> bool {
         match self.0 {
             Some(ref mut path) => {
@@ -109,6 +156,23 @@ impl Context {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Regular,
+    Symlink,
+    Directory,
+}
+
+/// Everything about an entry except the contents of regular files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub path: Option<Utf8PathBuf>,
+    pub kind: Kind,
+    pub size: u64,
+    pub executable: bool,
+    pub target: Option<String>,
+}
+
 #[derive(derive_more::Debug)]
 #[allow(dead_code)]
 pub enum Contents<'a, R> {
@@ -129,6 +193,17 @@ impl<R: AsyncRead> Reader<R> {
         Reader {
             inner: Teller::new(Box::pin(r)),
             state: Some(State::Start),
+            entries: 0,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+
+    /// Fail once the archive has more than `max` entries, rather than
+    /// `DEFAULT_MAX_ENTRIES`.
+    pub fn with_max_entries(self, max: u64) -> Reader<R> {
+        Reader {
+            max_entries: max,
+            ..self
         }
     }
 
@@ -150,24 +225,59 @@ impl<R: AsyncRead> Reader<R> {
         Ok(())
     }
 
+    /// Read a regular file's fields up to its contents. The reference format
+    /// marks executables with `"executable", ""`, but some writers leave out
+    /// the empty string, so it is optional here.
     async fn regular_header(&mut self) -> anyhow::Result<(bool, u64)> {
         let mut executable = false;
         let mut s = self.read_str().await?;
         if s == "executable" {
             executable = true;
-            self.expect_str("").await?;
             s = self.read_str().await?;
+            if s.is_empty() {
+                s = self.read_str().await?;
+            }
         }
         if s != "contents" {
-            bail!("expected '{}', got '{}'", "contents", s);
+            let after = if executable {
+                "'executable'"
+            } else {
+                "'type regular'"
+            };
+            bail!("expected 'contents' after {after} in regular file, got '{s}'");
         }
 
         let size = self.inner.read_u64_le().await?;
         Ok((executable, size))
     }
 
-    pub async fn next<'a>(&'a mut self) -> anyhow::Result<Option<Entry<'a, R>>> {
+    /// Advance to the next entry without handing out its contents. Skipping
+    /// over a regular file this way doesn't require reading it.
+    pub async fn next_metadata(&mut self) -> anyhow::Result<Option<Metadata>> {
+        self.advance(None).await
+    }
+
+    /// Skip the remaining children of the directory that was just returned,
+    /// without yielding any of them.
+    pub async fn skip_subtree(&mut self) -> anyhow::Result<()> {
+        let Some(State::Directory { context }) = &self.state else {
+            bail!("the current entry is not a directory");
+        };
+        let depth = context.depth();
+        while self.advance(Some(depth)).await?.is_some() {}
+        Ok(())
+    }
+
+    /// Run the state machine until the next entry. If `stop_depth` is set,
+    /// stop early once the directory at that depth has been closed.
+    async fn advance(&mut self, stop_depth: Option<usize>) -> anyhow::Result<Option<Metadata>> {
         loop {
+            if let (Some(depth), Some(State::ObjectEnd { context })) = (stop_depth, &self.state)
+                && context.depth() == depth
+            {
+                return Ok(None);
+            }
+
             match self.state.take().expect("missing state") {
                 State::Start => {
                     self.expect_str("nix-archive-1").await?;
@@ -176,6 +286,10 @@ impl<R: AsyncRead> Reader<R> {
                     });
                 }
                 State::Object { context } => {
+                    self.entries += 1;
+                    if self.entries > self.max_entries {
+                        bail!("NAR has more than {} entries", self.max_entries);
+                    }
                     let path = context.0.clone();
                     self.expect_str("(").await?;
                     self.expect_str("type").await?;
@@ -184,23 +298,24 @@ impl<R: AsyncRead> Reader<R> {
                         "regular" => {
                             let (executable, size) = self.regular_header().await?;
                             let offset = self.inner.position + size.next_multiple_of(8);
-                            let data = (&mut self.inner).take(size);
 
                             self.state = Some(State::Regular { offset, context });
-                            return Ok(Some(Entry {
+                            return Ok(Some(Metadata {
                                 path,
-                                contents: Contents::Regular {
-                                    executable,
-                                    size,
-                                    data,
-                                },
+                                kind: Kind::Regular,
+                                size,
+                                executable,
+                                target: None,
                             }));
                         }
                         "directory" => {
                             self.state = Some(State::Directory { context });
-                            return Ok(Some(Entry {
+                            return Ok(Some(Metadata {
                                 path,
-                                contents: Contents::Directory,
+                                kind: Kind::Directory,
+                                size: 0,
+                                executable: false,
+                                target: None,
                             }));
                         }
                         "symlink" => {
@@ -208,9 +323,12 @@ impl<R: AsyncRead> Reader<R> {
                             let target = self.read_str().await?;
                             self.expect_str(")").await?;
                             self.state = Some(State::ObjectEnd { context });
-                            return Ok(Some(Entry {
+                            return Ok(Some(Metadata {
                                 path,
-                                contents: Contents::Symlink { target },
+                                kind: Kind::Symlink,
+                                size: 0,
+                                executable: false,
+                                target: Some(target),
                             }));
                         }
                         t => bail!("invalid entry type: {}", t),
@@ -241,6 +359,11 @@ impl<R: AsyncRead> Reader<R> {
                         self.expect_str(")").await?;
                         self.state = Some(State::Directory { context });
                     } else {
+                        // Reading up to the end lets the source check what
+                        // it sent, such as the hash of a downloaded NAR.
+                        if self.inner.read(&mut [0; 1]).await? != 0 {
+                            bail!("unexpected data after the end of the NAR");
+                        }
                         self.state = Some(State::Closed);
                     }
                 }
@@ -249,8 +372,56 @@ impl<R: AsyncRead> Reader<R> {
         }
     }
 
-    pub async fn extract(&mut self, root: impl AsRef<Path>) -> anyhow::Result<()> {
+    /// Turn the metadata just returned by `next_metadata` into an entry,
+    /// whose contents read from the current position.
+    pub fn entry(&mut self, metadata: Metadata) -> Entry<'_, R> {
+        let contents = match metadata.kind {
+            Kind::Regular => Contents::Regular {
+                executable: metadata.executable,
+                size: metadata.size,
+                data: (&mut self.inner).take(metadata.size),
+            },
+            Kind::Symlink => Contents::Symlink {
+                target: metadata.target.expect("symlink without a target"),
+            },
+            Kind::Directory => Contents::Directory,
+        };
+        Entry {
+            path: metadata.path,
+            contents,
+        }
+    }
+
+    pub async fn next(&mut self) -> anyhow::Result<Option<Entry<'_, R>>> {
+        match self.next_metadata().await? {
+            Some(metadata) => Ok(Some(self.entry(metadata))),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the metadata of the entry at `path`, relative to the root of the
+    /// archive. An empty path refers to the root itself. Directories which
+    /// cannot contain `path` are skipped without being enumerated.
+    pub async fn find_metadata(
+        &mut self,
+        path: impl AsRef<Utf8Path>,
+    ) -> anyhow::Result<Option<Metadata>> {
+        let path = path.as_ref();
+        while let Some(metadata) = self.next_metadata().await? {
+            let current = metadata.path.as_deref().unwrap_or(Utf8Path::new(""));
+            if current == path {
+                return Ok(Some(metadata));
+            }
+            if metadata.kind == Kind::Directory && !path.starts_with(current) {
+                self.skip_subtree().await?;
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn extract(&mut self, root: impl AsRef<Path>) -> anyhow::Result<ExtractStats> {
         let root = root.as_ref();
+        let mut stats = ExtractStats::default();
         while let Some(entry) = self.next().await? {
             let dst = match entry.path {
                 Some(p) => root.join(p),
@@ -272,22 +443,111 @@ impl<R: AsyncRead> Reader<R> {
                         .await
                         .with_context(|| format!("Cannot create file {}", dst.display()))?;
 
-                    tokio::io::copy(&mut data, &mut output).await?;
+                    stats.bytes += tokio::io::copy(&mut data, &mut output).await?;
+                    stats.files += 1;
                 }
 
                 Contents::Symlink { target } => {
                     tokio::fs::symlink(&target, &dst).await.with_context(|| {
                         format!("Cannot create symbolic link {}", dst.display())
                     })?;
+                    stats.symlinks += 1;
                 }
 
                 Contents::Directory => {
                     tokio::fs::create_dir(&dst)
                         .await
                         .with_context(|| format!("Cannot create directory {}", dst.display()))?;
+                    stats.directories += 1;
                 }
             }
         }
+        stats.input_wait = self.inner.waited;
+        Ok(stats)
+    }
+}
+
+pub struct Writer<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    pub fn new(w: W) -> Writer<W> {
+        Writer { inner: w }
+    }
+
+    async fn write_padding(&mut self, n: u64) -> anyhow::Result<()> {
+        let padding = (n.next_multiple_of(8) - n) as usize;
+        self.inner.write_all(&[0u8; 8][..padding]).await?;
+        Ok(())
+    }
+
+    async fn write_str(&mut self, s: impl AsRef<[u8]>) -> anyhow::Result<()> {
+        let s = s.as_ref();
+        self.inner.write_u64_le(s.len() as u64).await?;
+        self.inner.write_all(s).await?;
+        self.write_padding(s.len() as u64).await
+    }
+
+    async fn write_node(&mut self, path: &Path) -> anyhow::Result<()> {
+        let metadata = tokio::fs::symlink_metadata(path)
+            .await
+            .with_context(|| format!("Cannot stat {}", path.display()))?;
+
+        self.write_str("(").await?;
+        self.write_str("type").await?;
+        if metadata.is_symlink() {
+            let target = tokio::fs::read_link(path).await?;
+            self.write_str("symlink").await?;
+            self.write_str("target").await?;
+            self.write_str(target.as_os_str().as_bytes()).await?;
+        } else if metadata.is_dir() {
+            self.write_str("directory").await?;
+
+            let mut names = vec![];
+            let mut entries = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                names.push(entry.file_name());
+            }
+            names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+            for name in names {
+                self.write_str("entry").await?;
+                self.write_str("(").await?;
+                self.write_str("name").await?;
+                self.write_str(name.as_bytes()).await?;
+                self.write_str("node").await?;
+                Box::pin(self.write_node(&path.join(&name))).await?;
+                self.write_str(")").await?;
+            }
+        } else if metadata.is_file() {
+            self.write_str("regular").await?;
+            if metadata.permissions().mode() & 0o100 != 0 {
+                self.write_str("executable").await?;
+                self.write_str("").await?;
+            }
+            self.write_str("contents").await?;
+
+            let size = metadata.len();
+            self.inner.write_u64_le(size).await?;
+            let file = tokio::fs::File::open(path).await?;
+            let copied = tokio::io::copy(&mut file.take(size), &mut self.inner).await?;
+            if copied != size {
+                bail!("{} changed size while packing it", path.display());
+            }
+            self.write_padding(size).await?;
+        } else {
+            bail!("{} has an unsupported file type", path.display());
+        }
+        self.write_str(")").await?;
+
+        Ok(())
+    }
+
+    pub async fn pack(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.write_str("nix-archive-1").await?;
+        self.write_node(path.as_ref()).await?;
+        self.inner.flush().await?;
         Ok(())
     }
 }
@@ -295,23 +555,12 @@ impl<R: AsyncRead> Reader<R> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use anyhow::Context as _;
-    use std::ffi::OsStr;
-    use std::process::Stdio;
     use tempfile::tempdir;
-    use tokio::process::Command;
 
-    async fn create_nar(path: impl AsRef<OsStr>) -> anyhow::Result<impl AsyncRead> {
-        let child = Command::new("nix")
-            .arg("nar")
-            .arg("pack")
-            .args(["--extra-experimental-features", "nix-command"])
-            .arg(path)
-            .stdin(Stdio::null())
-            .stdout(Stdio::piped())
-            .spawn()
-            .context("Could not run nix command")?;
-        Ok(child.stdout.unwrap())
+    async fn create_nar(path: impl AsRef<Path>) -> anyhow::Result<impl AsyncRead> {
+        let mut buf = vec![];
+        Writer::new(&mut buf).pack(path).await?;
+        Ok(std::io::Cursor::new(buf))
     }
 
     async fn enumerate_nar(
@@ -343,6 +592,56 @@ mod tests {
         Ok(())
     }
 
+    fn nar_strings(strings: &[&str]) -> Vec<u8> {
+        let mut buf = vec![];
+        for s in strings {
+            buf.extend((s.len() as u64).to_le_bytes());
+            buf.extend(s.as_bytes());
+            buf.resize(buf.len().next_multiple_of(8), 0);
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn nar_executable_without_empty_string() -> anyhow::Result<()> {
+        let nar = nar_strings(&[
+            "nix-archive-1",
+            "(",
+            "type",
+            "regular",
+            "executable",
+            "contents",
+            "hi",
+            ")",
+        ]);
+        let mut reader = Reader::new(std::io::Cursor::new(nar));
+        let entry = reader.next().await?.unwrap();
+        let Contents::Regular {
+            executable,
+            mut data,
+            ..
+        } = entry.contents
+        else {
+            panic!("expected a regular file");
+        };
+        assert!(executable);
+        let mut contents = String::new();
+        data.read_to_string(&mut contents).await?;
+        assert_eq!(contents, "hi");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nar_regular_header_error() {
+        let nar = nar_strings(&["nix-archive-1", "(", "type", "regular", "executable", "x"]);
+        let mut reader = Reader::new(std::io::Cursor::new(nar));
+        let e = reader.next().await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "expected 'contents' after 'executable' in regular file, got 'x'"
+        );
+    }
+
     #[tokio::test]
     async fn nar_symlink() -> anyhow::Result<()> {
         let root = tempdir()?;
@@ -389,4 +688,231 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn nar_extract_stats() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::write(root.path().join("hello.txt"), "Hello")?;
+        std::fs::create_dir(root.path().join("nested"))?;
+        std::fs::write(root.path().join("nested/world.txt"), "World!")?;
+        std::os::unix::fs::symlink("hello.txt", root.path().join("link"))?;
+
+        let dst = tempdir()?;
+        let stats = Reader::new(create_nar(root.path()).await?)
+            .extract(dst.path().join("out"))
+            .await?;
+        assert_eq!(
+            stats,
+            ExtractStats {
+                files: 2,
+                directories: 2,
+                symlinks: 1,
+                bytes: 11,
+                input_wait: stats.input_wait,
+            }
+        );
+
+        Ok(())
+    }
+
+    /// An empty file has no contents and no padding, which the reader must
+    /// step over without losing its place.
+    #[tokio::test]
+    async fn nar_empty_file() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::write(root.path().join("a"), "")?;
+        std::fs::write(root.path().join("b"), "not empty")?;
+
+        let result = enumerate_nar(create_nar(root.path()).await?).await?;
+        assert_eq!(
+            result,
+            vec![
+                (None, 'd'),
+                (Some(Utf8PathBuf::from("a")), 'f'),
+                (Some(Utf8PathBuf::from("b")), 'f')
+            ]
+        );
+
+        let dst = tempdir()?;
+        let dst = dst.path().join("out");
+        let stats = Reader::new(create_nar(root.path()).await?)
+            .extract(&dst)
+            .await?;
+        assert_eq!((stats.files, stats.bytes), (2, 9));
+        assert_eq!(std::fs::read(dst.join("a"))?, b"");
+        assert_eq!(std::fs::read(dst.join("b"))?, b"not empty");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nar_max_entries() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::write(root.path().join("a"), "")?;
+        std::fs::write(root.path().join("b"), "")?;
+
+        let dst = tempdir()?;
+        let e = Reader::new(create_nar(root.path()).await?)
+            .with_max_entries(2)
+            .extract(dst.path().join("too-many"))
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "NAR has more than 2 entries");
+
+        let stats = Reader::new(create_nar(root.path()).await?)
+            .with_max_entries(3)
+            .extract(dst.path().join("out"))
+            .await?;
+        assert_eq!(stats.files, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nar_compressed() -> anyhow::Result<()> {
+        use crate::binary_cache::Compression;
+        use async_compression::tokio::bufread::XzEncoder;
+
+        let root = tempdir()?;
+        std::fs::write(root.path().join("hello.txt"), "Hello")?;
+
+        let stream = create_nar(root.path()).await?;
+        let mut compressed = vec![];
+        XzEncoder::new(tokio::io::BufReader::new(stream))
+            .read_to_end(&mut compressed)
+            .await?;
+
+        let mut reader = Reader::new(Compression::Xz.decode(&compressed[..]));
+        let mut result = vec![];
+        while let Some(entry) = reader.next().await? {
+            result.push(entry.path);
+        }
+        assert_eq!(result, vec![None, Some(Utf8PathBuf::from("hello.txt"))]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nar_find_metadata() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::write(root.path().join("hello.txt"), "Hello")?;
+        std::fs::set_permissions(
+            root.path().join("hello.txt"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        std::os::unix::fs::symlink("hello.txt", root.path().join("link"))?;
+
+        let mut reader = Reader::new(create_nar(root.path()).await?);
+        let metadata = reader.find_metadata("hello.txt").await?.unwrap();
+        assert_eq!(metadata.kind, Kind::Regular);
+        assert_eq!(metadata.size, 5);
+        assert!(metadata.executable);
+
+        let metadata = reader.find_metadata("link").await?.unwrap();
+        assert_eq!(metadata.kind, Kind::Symlink);
+        assert_eq!(metadata.target.as_deref(), Some("hello.txt"));
+
+        let mut reader = Reader::new(create_nar(root.path()).await?);
+        assert_eq!(reader.find_metadata("missing").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nar_entry_after_find_metadata() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::write(root.path().join("a.txt"), "first")?;
+        std::fs::create_dir(root.path().join("nested"))?;
+        std::fs::write(root.path().join("nested/z.txt"), "last")?;
+
+        async fn read(reader: &mut Reader<impl AsyncRead>, path: &str) -> anyhow::Result<String> {
+            let Some(metadata) = reader.find_metadata(path).await? else {
+                bail!("{path} not found");
+            };
+            let Contents::Regular { mut data, .. } = reader.entry(metadata).contents else {
+                bail!("{path} is not a regular file");
+            };
+            let mut s = String::new();
+            data.read_to_string(&mut s).await?;
+            Ok(s)
+        }
+
+        let mut reader = Reader::new(create_nar(root.path()).await?);
+        assert_eq!(read(&mut reader, "a.txt").await?, "first");
+        assert_eq!(read(&mut reader, "nested/z.txt").await?, "last");
+        assert!(reader.find_metadata("a.txt").await?.is_none());
+
+        let mut reader = Reader::new(create_nar(root.path()).await?);
+        let metadata = reader.find_metadata("nested").await?.unwrap();
+        assert!(matches!(
+            reader.entry(metadata).contents,
+            Contents::Directory
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nar_skip_subtree() -> anyhow::Result<()> {
+        let root = tempdir()?;
+        std::fs::create_dir(root.path().join("a"))?;
+        std::fs::write(root.path().join("a/hello.txt"), "Hello")?;
+        std::fs::create_dir(root.path().join("a/nested"))?;
+        std::fs::write(root.path().join("a/nested/world.txt"), "World")?;
+        std::fs::write(root.path().join("b.txt"), "b")?;
+
+        let mut reader = Reader::new(create_nar(root.path()).await?);
+        let mut result = vec![];
+        while let Some(metadata) = reader.next_metadata().await? {
+            if metadata.path.as_deref() == Some(Utf8Path::new("a")) {
+                reader.skip_subtree().await?;
+            }
+            result.push(metadata.path);
+        }
+        assert_eq!(
+            result,
+            vec![
+                None,
+                Some(Utf8PathBuf::from("a")),
+                Some(Utf8PathBuf::from("b.txt"))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "nix-tests")]
+    #[tokio::test]
+    async fn nar_matches_nix() -> anyhow::Result<()> {
+        use std::process::Stdio;
+        use tokio::process::Command;
+
+        let root = tempdir()?;
+        std::fs::write(root.path().join("hello.txt"), "Hello")?;
+        std::fs::write(root.path().join("run.sh"), "#!/bin/sh\n")?;
+        std::fs::set_permissions(
+            root.path().join("run.sh"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        std::fs::create_dir(root.path().join("nested"))?;
+        std::fs::write(root.path().join("nested/world.txt"), "World")?;
+        std::os::unix::fs::symlink("../hello.txt", root.path().join("nested/link"))?;
+
+        let output = Command::new("nix")
+            .arg("nar")
+            .arg("pack")
+            .args(["--extra-experimental-features", "nix-command"])
+            .arg(root.path())
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .context("Could not run nix command")?;
+        assert!(output.status.success());
+
+        let mut ours = vec![];
+        Writer::new(&mut ours).pack(root.path()).await?;
+        assert_eq!(ours, output.stdout);
+
+        Ok(())
+    }
 }
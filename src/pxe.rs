@@ -1,30 +1,71 @@
-use crate::binary_cache::{self, BinaryCache};
-use crate::config::Config;
+use crate::binary_cache::{self, BinaryCache, Compression, Progress};
+use crate::blocking;
+use crate::config::{self, Config, MacAddr, PinSourceCache, SharedConfig, StoreLayout};
+use crate::error::ApiError;
+use crate::metrics;
+use crate::nar;
+use crate::query::deserialize_flag;
+use crate::rate_limit::RateLimiter;
+use crate::request_id;
 use crate::store::Store;
+use crate::store_path::{self, StorePath};
+use crate::tls;
 
-use anyhow::{anyhow, bail};
+use anyhow::{Context as _, anyhow, bail};
+use arc_swap::ArcSwap;
 use axum::Json;
-use axum::extract::Query;
+use axum::body::Body;
 use axum::extract::Request;
+use axum::extract::{ConnectInfo, Query};
 use axum::extract::{Path, State};
 use axum::response::{IntoResponse, Response};
+use axum_extra::middleware::option_layer;
 use axum_extra::{json, response::ErasedJson};
 use base64::{Engine as _, engine::general_purpose::URL_SAFE};
-use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use camino::{Utf8Path, Utf8PathBuf};
+use futures::StreamExt;
 use hmac::{Hmac, Mac};
 use http::StatusCode;
 use rand::RngCore;
-use regex::Regex;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest as _, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::sync::Semaphore;
+use tokio_util::io::ReaderStream;
+use tracing::Instrument as _;
 use url::Url;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct LastRevision {
     pub store_path: String,
+    /// What the revision was pushed as, such as a git commit. Cachix numbers
+    /// revisions unless told otherwise.
+    #[serde(default, deserialize_with = "deserialize_revision")]
+    pub revision: Option<String>,
+}
+
+fn deserialize_revision<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Revision {
+        Text(String),
+        Number(u64),
+    }
+    Ok(
+        Option::<Revision>::deserialize(deserializer)?.map(|r| match r {
+            Revision::Text(s) => s,
+            Revision::Number(n) => n.to_string(),
+        }),
+    )
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -34,77 +75,385 @@ pub struct CachixPin {
     pub last_revision: LastRevision,
 }
 
-fn parse_store_path(path: impl AsRef<Utf8Path>) -> anyhow::Result<(String, Utf8PathBuf)> {
-    let path = path.as_ref();
-    let Ok(path) = path.strip_prefix(std::path::Path::new("/nix/store")) else {
-        return Err(anyhow!("{} is not a store path", path).into());
-    };
+/// The part of a Cachix cache's description needed to download from it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CachixCache {
+    pub uri: Url,
+}
 
-    let mut components = path.components();
-    let Some(Utf8Component::Normal(hashname)) = components.next() else {
-        return Err(anyhow!("bad path").into());
-    };
+fn http_client(config: &config::Http) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(config.read_timeout_secs));
+    if let Some(secs) = config.request_timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = config.pool_idle_timeout_secs {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(max) = config.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max);
+    }
+    if let Some(secs) = config.tcp_keepalive_secs {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+    }
+    if config.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    Ok(builder.build()?)
+}
 
-    let re = Regex::new(r"^(?<hash>[0-9a-z]{32})-[-.+_?=0-9a-zA-Z]+$").expect("regex to be valid");
-    let Some(m) = re.captures(hashname) else {
-        bail!("bad symlink");
-    };
+#[derive(Clone)]
+struct ResolvedPin {
+    hash: String,
+    store_path: String,
+    /// The Cachix cache the pin was found in, unless it is a fixed fallback.
+    cache: Option<String>,
+}
 
-    let hash = m
-        .name("hash")
-        .expect("hash capture to exist")
-        .as_str()
-        .to_owned();
-    let suffix = components.as_path().to_owned();
+/// The last list of pins fetched from a Cachix cache, and what to ask Cachix
+/// whether it has changed with.
+struct PinList {
+    etag: Option<http::HeaderValue>,
+    last_modified: Option<http::HeaderValue>,
+    pins: Arc<Vec<CachixPin>>,
+}
+
+/// The pins of the Cachix cache `cache`. Requests are made conditional on
+/// the previous answer, so that during a burst of boots an unchanged list
+/// is a 304 rather than the whole list again.
+async fn fetch_pins(state: &PxeState, cache: &str) -> anyhow::Result<Arc<Vec<CachixPin>>> {
+    use http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+
+    let mut request = state.client.get(state.cachix_url(cache).join("pin")?);
+    if let Some(previous) = state.pin_lists.lock().unwrap().get(cache) {
+        if let Some(etag) = &previous.etag {
+            request = request.header(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &previous.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+    let r = request.send().await?;
 
-    Ok((hash, suffix))
+    if r.status() == StatusCode::NOT_MODIFIED {
+        return match state.pin_lists.lock().unwrap().get(cache) {
+            Some(previous) => Ok(previous.pins.clone()),
+            None => bail!("cachix answered 304 to an unconditional request"),
+        };
+    }
+    let r = r.error_for_status()?;
+    let etag = r.headers().get(ETAG).cloned();
+    let last_modified = r.headers().get(LAST_MODIFIED).cloned();
+    let pins: Arc<Vec<CachixPin>> = Arc::new(r.json().await?);
+    if etag.is_some() || last_modified.is_some() {
+        state.pin_lists.lock().unwrap().insert(
+            cache.to_owned(),
+            PinList {
+                etag,
+                last_modified,
+                pins: pins.clone(),
+            },
+        );
+    }
+    Ok(pins)
 }
 
+/// The pin called `name` in the Cachix cache `cache`, if it exists and, when
+/// `revision` is given, its latest revision is that one.
 async fn find_cachix_pin(
-    client: &reqwest::Client,
-    url: &Url,
+    state: &PxeState,
+    cache: &str,
     name: &str,
-) -> anyhow::Result<String> {
+    revision: Option<&str>,
+) -> anyhow::Result<Option<CachixPin>> {
+    let pins = fetch_pins(state, cache).await?;
+    let pin = pins.iter().find(|pin| pin.name == name).cloned();
+    Ok(pin.filter(|pin| {
+        let Some(revision) = revision else {
+            return true;
+        };
+        let latest = pin.last_revision.revision.as_deref();
+        if latest != Some(revision) {
+            tracing::info!(
+                name,
+                revision,
+                latest,
+                "pin is not at the requested revision"
+            );
+            return false;
+        }
+        true
+    }))
+}
+
+async fn find_cachix_cache(client: &reqwest::Client, url: &Url) -> anyhow::Result<CachixCache> {
+    // Unlike its sub-resources, the cache itself has no trailing slash.
     let r = client
-        .get(url.join("pin")?)
+        .get(url.as_str().trim_end_matches('/'))
         .send()
         .await?
         .error_for_status()?;
+    Ok(r.json().await?)
+}
+
+/// Remember that `hash` was pinned in the Cachix cache `cache`, so that it
+/// can be downloaded from there if the binary caches don't have it yet.
+async fn record_pin_source(state: &PxeState, hash: &str, cache: &str) {
+    if state.config.load().pxe.pin_source_cache == PinSourceCache::Never {
+        return;
+    }
+
+    let known = state.cachix_caches.lock().unwrap().contains_key(cache);
+    if !known {
+        match find_cachix_cache(&state.client, &state.cachix_url(cache)).await {
+            Ok(c) => {
+                let config = state.config.load();
+                let cache_for_pin = BinaryCache::new(
+                    c.uri,
+                    config.pxe.max_nar_bytes,
+                    config.pxe.narinfo_accept.clone(),
+                );
+                state
+                    .cachix_caches
+                    .lock()
+                    .unwrap()
+                    .insert(cache.to_owned(), cache_for_pin);
+            }
+            Err(e) => {
+                tracing::warn!(?e, cache, "cannot find the binary cache of cachix cache");
+                return;
+            }
+        }
+    }
+
+    state
+        .pin_sources
+        .lock()
+        .unwrap()
+        .insert(hash.to_owned(), cache.to_owned());
+}
 
-    let body: Vec<CachixPin> = r.json().await?;
-    let Some(pin) = body.into_iter().find(|pin| pin.name == name) else {
+/// Look up the pin for `name` in each configured Cachix cache, in order,
+/// skipping those where it isn't at `revision`. Returns `None` only if every
+/// cache could be asked and none has it.
+async fn find_pin(
+    state: &PxeState,
+    name: &str,
+    revision: Option<&str>,
+) -> anyhow::Result<Option<ResolvedPin>> {
+    let mut error = None;
+    let config = state.config.load_full();
+    for cache in &config.pxe.cachix {
+        match find_cachix_pin(state, cache, name, revision).await {
+            Ok(Some(pin)) => {
+                let store_path = StorePath::parse(&pin.last_revision.store_path)?;
+                record_pin_source(state, &store_path.hash, cache).await;
+                return Ok(Some(ResolvedPin {
+                    hash: store_path.hash,
+                    store_path: pin.last_revision.store_path,
+                    cache: Some(cache.clone()),
+                }));
+            }
+            Ok(None) => (),
+            Err(e) => {
+                tracing::warn!(?e, cache, "cannot fetch pins from cachix");
+                error = Some(e);
+            }
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// The pin for host `name`, at its `pin_revision` if it has one, or
+/// `pxe.fallback` if there is no such pin. A host held at a revision no
+/// cache pins any more fails to boot rather than boot something else.
+async fn resolve_pin(state: &PxeState, name: &str) -> anyhow::Result<ResolvedPin> {
+    let config = state.config.load_full();
+    let revision = config
+        .host
+        .get(name)
+        .and_then(|h| h.pin_revision.as_deref());
+    if let Some(pin) = find_pin(state, name, revision).await? {
+        return Ok(pin);
+    }
+    if let Some(revision) = revision {
+        bail!("no cachix cache pins {name} at revision {revision}");
+    }
+
+    let Some(fallback) = &config.pxe.fallback else {
         bail!("pin not found");
     };
+    tracing::info!(name, fallback, "no pin, booting the fallback");
+    if fallback.starts_with('/') {
+        let store_path = StorePath::parse(fallback)?;
+        return Ok(ResolvedPin {
+            hash: store_path.hash,
+            store_path: fallback.clone(),
+            cache: None,
+        });
+    }
+    find_pin(state, fallback, None)
+        .await?
+        .ok_or_else(|| anyhow!("fallback pin {fallback} not found"))
+}
+
+/// Look up a host's pin in the background-refreshed cache, resolving it
+/// live on a miss or if background refreshing is disabled.
+async fn cached_pin(state: &PxeState, name: &str) -> anyhow::Result<ResolvedPin> {
+    if state.pin_refresh.is_none() {
+        return resolve_pin(state, name).await;
+    }
 
-    let (hash, _) = parse_store_path(&pin.last_revision.store_path)?;
-    Ok(hash)
+    if let Some(pin) = state.pins.lock().unwrap().get(name) {
+        return Ok(pin.clone());
+    }
+
+    let pin = resolve_pin(state, name).await?;
+    state
+        .pins
+        .lock()
+        .unwrap()
+        .insert(name.to_owned(), pin.clone());
+    Ok(pin)
 }
 
-async fn download_path(state: &PxeState, hash: &str) -> anyhow::Result<PathBuf> {
-    match state.store.lookup(hash).await? {
-        Some(p) => {
-            println!("{hash} already exists in store");
-            Ok(p)
-        }
-        None => {
-            let nar = binary_cache::download(&state.client, &state.caches, &hash).await?;
-            Ok(state.store.add(hash, nar).await?)
+async fn refresh_pins(state: Pxe, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let config = state.config.load_full();
+        for hostname in config.host.keys() {
+            match resolve_pin(&state, hostname).await {
+                Ok(pin) => {
+                    state.pins.lock().unwrap().insert(hostname.clone(), pin);
+                }
+                // Keep serving the previous pin until cachix comes back.
+                Err(e) => tracing::warn!(?e, hostname, "cannot refresh pin"),
+            }
         }
     }
 }
 
-async fn download_file(
+#[derive(Default)]
+struct DownloadOptions<'a> {
+    /// Re-download the path even if it is already present in the store.
+    force: bool,
+    progress: Option<Progress>,
+    /// The host the path is needed for, to tell in logs and metrics.
+    host: Option<&'a str>,
+}
+
+async fn download_path(
+    state: &PxeState,
+    hash: &str,
+    host: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    let options = DownloadOptions {
+        host,
+        ..Default::default()
+    };
+    download_path_with(state, hash, options).await
+}
+
+/// Count a NAR download towards `host`, and give a span to log it in.
+fn download_span(hash: &str, host: Option<&str>) -> tracing::Span {
+    metrics::NAR_DOWNLOADS
+        .with_label_values(&[host.unwrap_or("")])
+        .inc();
+    tracing::info_span!("download", hash, host)
+}
+
+async fn download_path_with(
+    state: &PxeState,
+    hash: &str,
+    options: DownloadOptions<'_>,
+) -> anyhow::Result<PathBuf> {
+    let Some(store) = &state.store else {
+        bail!("cannot download {hash}, there is no store to extract it into");
+    };
+
+    if !options.force
+        && let Some(p) = store.lookup(hash).await?
+    {
+        println!("{hash} already exists in store");
+        metrics::STORE_LOOKUPS.with_label_values(&["hit"]).inc();
+        return Ok(p);
+    }
+    if state.read_only_store {
+        metrics::STORE_LOOKUPS.with_label_values(&["miss"]).inc();
+        return Err(NotCached(hash.to_owned()).into());
+    }
+
+    // Only one request downloads a given path; the others wait for it and
+    // then find it in the store.
+    let _guard = store.lock_path(hash).await;
+    if !options.force
+        && let Some(p) = store.lookup(hash).await?
+    {
+        metrics::STORE_LOOKUPS.with_label_values(&["hit"]).inc();
+        return Ok(p);
+    }
+    metrics::STORE_LOOKUPS
+        .with_label_values(&["download"])
+        .inc();
+
+    let _permit = match &state.download_limit {
+        Some(semaphore) => Some(semaphore.acquire().await?),
+        None => None,
+    };
+
+    async {
+        let nar = binary_cache::download(
+            &state.client,
+            &state.caches_for(hash),
+            hash,
+            &state.compression_preference,
+            options.progress,
+        )
+        .await?;
+        // Extracting is mostly CPU-bound too, so it runs off the runtime
+        // along with decoding.
+        let (store, hash) = (store.clone(), hash.to_owned());
+        blocking::run(
+            async move {
+                if options.force {
+                    store.replace(&hash, nar).await
+                } else {
+                    store.add(&hash, nar).await
+                }
+            }
+            .in_current_span(),
+        )
+        .await
+    }
+    .instrument(download_span(hash, options.host))
+    .await
+}
+
+/// Find the file at `path` within the store path `hash`, downloading it for
+/// `host` and following symbolic links as necessary. Returns `None` if it
+/// doesn't exist.
+async fn resolve_file(
     state: &PxeState,
     hash: &str,
     path: impl Into<Utf8PathBuf>,
-) -> Result<Vec<u8>, PxeError> {
+    host: Option<&str>,
+) -> Result<Option<PathBuf>, PxeError> {
     let mut hash = hash.to_owned();
     let mut path = path.into();
 
     loop {
-        let base = download_path(state, &hash).await?;
+        let base = download_path(state, &hash, host).await?;
         let p = base.join(&path);
-        let metadata = tokio::fs::symlink_metadata(&p).await?;
+        let metadata = match tokio::fs::symlink_metadata(&p).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
         if metadata.is_dir() {
             return Err(anyhow!("{} is a directory", path).into());
         } else if metadata.is_symlink() {
@@ -112,133 +461,880 @@ async fn download_file(
             let target = Utf8Path::from_path(&target).unwrap();
 
             // TODO: support targets other than absolute /nix/store
-            (hash, path) = parse_store_path(&target)?;
+            let target = StorePath::parse(target).map_err(anyhow::Error::from)?;
+            state.inherit_pin_source(&hash, &target.hash);
+            (hash, path) = (target.hash, target.suffix);
             println!("Following symbolic link to {hash}/{path}");
         } else {
-            return Ok(tokio::fs::read(p).await?);
+            // Symbolic links in the middle of `path` could still lead elsewhere.
+            let real = tokio::fs::canonicalize(&p).await?;
+            if !real.starts_with(tokio::fs::canonicalize(&base).await?) {
+                return Err(anyhow!("{hash}/{path} resolves outside of its store path").into());
+            }
+            return Ok(Some(p));
+        }
+    }
+}
+
+/// Locate the file at `path` within `hash` by reading its NAR straight from
+/// the binary cache, following symbolic links as necessary. On success, the
+/// reader is positioned just before the file's contents.
+async fn find_in_cache(
+    state: &PxeState,
+    hash: &str,
+    mut path: Utf8PathBuf,
+    host: Option<&str>,
+) -> Result<Option<(nar::Reader<impl AsyncRead + Send + use<>>, nar::Metadata)>, PxeError> {
+    let mut hash = hash.to_owned();
+
+    loop {
+        let nar = binary_cache::download(
+            &state.client,
+            &state.caches_for(&hash),
+            &hash,
+            &state.compression_preference,
+            None,
+        )
+        .instrument(download_span(&hash, host))
+        .await?;
+        let mut reader = nar::Reader::new(nar).with_max_entries(state.max_nar_entries);
+        let Some(metadata) = reader.find_metadata(&path).await? else {
+            return Ok(None);
+        };
+        match metadata.kind {
+            nar::Kind::Regular => return Ok(Some((reader, metadata))),
+            nar::Kind::Directory => return Err(anyhow!("{} is a directory", path).into()),
+            nar::Kind::Symlink => {
+                let target = metadata.target.unwrap_or_default();
+                let target = StorePath::parse(target)?;
+                state.inherit_pin_source(&hash, &target.hash);
+                (hash, path) = (target.hash, target.suffix);
+            }
         }
     }
 }
 
+/// Whether the file at `path` exists within `hash`.
+async fn file_exists(
+    state: &PxeState,
+    hash: &str,
+    path: &str,
+    host: Option<&str>,
+) -> Result<bool, PxeError> {
+    Ok(file_size(state, hash, path, host).await?.is_some())
+}
+
+/// The size of the file at `path` within `hash`, if there is one.
+async fn file_size(
+    state: &PxeState,
+    hash: &str,
+    path: &str,
+    host: Option<&str>,
+) -> Result<Option<u64>, PxeError> {
+    Ok(match state.store {
+        Some(_) => match resolve_file(state, hash, path, host).await? {
+            Some(p) => Some(tokio::fs::metadata(p).await?.len()),
+            None => None,
+        },
+        None => find_in_cache(state, hash, path.into(), host)
+            .await?
+            .map(|(_, metadata)| metadata.size),
+    })
+}
+
+/// The contents of the file at `path` within `hash`. Without a store, the
+/// file is streamed out of the NAR as it is being downloaded.
+async fn file_body(
+    state: &PxeState,
+    hash: &str,
+    path: impl Into<Utf8PathBuf>,
+    host: Option<&str>,
+) -> Result<Body, PxeError> {
+    let path = path.into();
+    if state.store.is_some() {
+        return match resolve_file(state, hash, &path, host).await? {
+            Some(p) => Ok(Body::from(tokio::fs::read(p).await?)),
+            None => Err(PxeError::NoSuchFile {
+                hash: hash.to_owned(),
+                path: path.into_string(),
+            }),
+        };
+    }
+
+    let Some((mut reader, metadata)) = find_in_cache(state, hash, path.clone(), host).await? else {
+        return Err(PxeError::NoSuchFile {
+            hash: hash.to_owned(),
+            path: path.into_string(),
+        });
+    };
+    let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        let nar::Contents::Regular { data, .. } = reader.entry(metadata).contents else {
+            unreachable!("find_in_cache only returns regular files");
+        };
+        // Errors are passed on so the response is aborted, rather than the
+        // client receiving a truncated file. The NAR's hash is only checked
+        // once all of it has been read, so the last chunk is held back until
+        // then, lest the client get the whole of a corrupt file.
+        let mut chunks = ReaderStream::new(data);
+        let mut last = None;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            if let Some(previous) = last.replace(chunk)
+                && tx.send(Ok(previous)).await.is_err()
+            {
+                return;
+            }
+        }
+        let end = async {
+            while reader.next_metadata().await?.is_some() {}
+            anyhow::Ok(())
+        };
+        let last = match end.await {
+            Ok(()) => last.map(Ok),
+            Err(e) => Some(Err(std::io::Error::other(e))),
+        };
+        if let Some(last) = last {
+            let _ = tx.send(last).await;
+        }
+    });
+    Ok(Body::from_stream(futures::stream::poll_fn(move |cx| {
+        rx.poll_recv(cx)
+    })))
+}
+
+async fn download_file(
+    state: &PxeState,
+    hash: &str,
+    path: impl Into<Utf8PathBuf>,
+    host: Option<&str>,
+) -> Result<Vec<u8>, PxeError> {
+    let body = file_body(state, hash, path, host).await?;
+    Ok(axum::body::to_bytes(body, usize::MAX).await?.into())
+}
+
 struct PxeState {
     caches: Vec<BinaryCache>,
+    /// The binary cache behind each Cachix cache pins were found in.
+    cachix_caches: Mutex<HashMap<String, BinaryCache>>,
+    /// The Cachix cache each pinned path, or a path one links to, came from.
+    pin_sources: Mutex<HashMap<String, String>>,
+    /// The latest pins of each Cachix cache, by name.
+    pin_lists: Mutex<HashMap<String, PinList>>,
     client: reqwest::Client,
-    config: Config,
+    compression_preference: Vec<Compression>,
+    config: SharedConfig,
+    file_rate_limiter: Option<RateLimiter>,
+    download_limit: Option<Semaphore>,
+    pins: Mutex<HashMap<String, ResolvedPin>>,
+    /// How often `pins` is refreshed in the background, if at all.
+    pin_refresh: Option<Duration>,
+    /// The prefix the routes are served under, which only changes on restart.
+    base_path: String,
     secret: [u8; 32],
-    store: Store,
+    /// Where NARs are extracted to, if anywhere.
+    store: Option<Arc<Store>>,
+    max_nar_entries: u64,
+    /// Whether `store` is filled by someone else, and only read from here.
+    read_only_store: bool,
 }
 type Pxe = Arc<PxeState>;
 
 impl PxeState {
-    fn mac_url(&self, hash: &str, path: &str) -> Hmac<Sha256> {
+    /// URLs are signed with the key in `pxe.secret_file`, or else one picked
+    /// at random, which makes them invalid after a restart.
+    fn new(config: SharedConfig, store: Option<Store>) -> anyhow::Result<PxeState> {
+        let secret = match &config.load().pxe.secret_file {
+            Some(path) => {
+                let key = std::fs::read(path)
+                    .with_context(|| format!("cannot read pxe.secret_file {}", path.display()))?;
+                if key.is_empty() {
+                    bail!("pxe.secret_file {} is empty", path.display());
+                }
+                // Keys of any length are hashed into one of the right size.
+                Sha256::digest(&key).into()
+            }
+            None => {
+                let mut secret = [0u8; 32];
+                rand::rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        PxeState::with_secret(config, store, secret)
+    }
+
+    /// A state without any hosts which downloads from `caches` into `store`,
+    /// and signs URLs with a fixed `secret`.
+    #[cfg(test)]
+    fn new_for_test(caches: &[Url], store: &std::path::Path, secret: [u8; 32]) -> PxeState {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "ipmi": { "username": "" },
+            "pxe": { "caches": caches, "cachix": [], "store": store },
+        }))
+        .unwrap();
+        PxeState::with_secret(
+            Arc::new(ArcSwap::from_pointee(config)),
+            Some(Store::new(store, false, StoreLayout::Flat)),
+            secret,
+        )
+        .unwrap()
+    }
+
+    /// Settings other than hosts and boot files are taken from `config` as it
+    /// is now, and don't change if it is reloaded.
+    fn with_secret(
+        shared: SharedConfig,
+        store: Option<Store>,
+        secret: [u8; 32],
+    ) -> anyhow::Result<PxeState> {
+        let config = shared.load();
+        Ok(PxeState {
+            client: http_client(&config.pxe.http)?,
+            caches: config
+                .pxe
+                .caches
+                .iter()
+                .map(|url| {
+                    BinaryCache::new(
+                        url.clone(),
+                        config.pxe.max_nar_bytes,
+                        config.pxe.narinfo_accept.clone(),
+                    )
+                })
+                .collect(),
+            cachix_caches: Mutex::new(HashMap::new()),
+            pin_sources: Mutex::new(HashMap::new()),
+            pin_lists: Mutex::new(HashMap::new()),
+            compression_preference: config
+                .pxe
+                .prefer_compression
+                .iter()
+                .map(|c| c.parse())
+                .collect::<Result<_, _>>()
+                .context("invalid pxe.prefer_compression")?,
+            store: store.map(|s| Arc::new(s.with_max_nar_entries(config.pxe.max_nar_entries))),
+            max_nar_entries: config.pxe.max_nar_entries,
+            read_only_store: config.pxe.read_only_store,
+            file_rate_limiter: config
+                .pxe
+                .file_rate_limit
+                .as_ref()
+                .map(|l| RateLimiter::new(l.per_minute, l.burst)),
+            download_limit: config.pxe.max_concurrent_downloads.map(Semaphore::new),
+            pins: Mutex::new(HashMap::new()),
+            pin_refresh: config.pxe.pin_refresh_secs.map(Duration::from_secs),
+            base_path: config.base_path().to_owned(),
+            config: shared.clone(),
+            secret,
+        })
+    }
+
+    fn mac_url(&self, hash: &str, path: &str, host: Option<&str>) -> Hmac<Sha256> {
         let mut mac = Hmac::new_from_slice(&self.secret).expect("Creating HMAC cannot fail");
-        mac.update(hash.as_bytes()); // TODO, bad
-        mac.update(path.as_bytes()); // TODO, bad
+        // Prefix each field with its length, so that no two different URLs
+        // sign the same bytes.
+        for field in [Some(hash), Some(path), host].into_iter().flatten() {
+            mac.update(&(field.len() as u64).to_be_bytes());
+            mac.update(field.as_bytes());
+        }
         mac
     }
 
-    fn file_url(&self, hash: &str, path: &str) -> String {
-        let key = self.mac_url(hash, path).finalize().into_bytes();
-        format!("/pxe/file/{hash}/{path}?key={}", URL_SAFE.encode(key))
+    /// A signed URL to `path` in `hash`. If `host` is given, it is part of the
+    /// URL, so that the file is served as it would be to that host.
+    fn file_url(&self, hash: &str, path: &str, host: Option<&str>) -> String {
+        let key = URL_SAFE.encode(self.mac_url(hash, path, host).finalize().into_bytes());
+        match host {
+            Some(host) => format!(
+                "{}/pxe/file/{hash}/{path}?host={}&key={key}",
+                self.base_path,
+                url::form_urlencoded::byte_serialize(host.as_bytes()).collect::<String>()
+            ),
+            None => format!("{}/pxe/file/{hash}/{path}?key={key}", self.base_path),
+        }
     }
 
-    fn verify_file_url(&self, hash: &str, path: &str, key: &str) -> anyhow::Result<()> {
+    fn verify_file_url(
+        &self,
+        hash: &str,
+        path: &str,
+        host: Option<&str>,
+        key: &str,
+    ) -> anyhow::Result<()> {
         let key = URL_SAFE.decode(key)?;
-        self.mac_url(hash, path).verify_slice(&key)?;
+        self.mac_url(hash, path, host).verify_slice(&key)?;
         Ok(())
     }
+
+    /// The binary caches to download `hash` from, in order.
+    fn caches_for(&self, hash: &str) -> Vec<BinaryCache> {
+        let source = self
+            .pin_sources
+            .lock()
+            .unwrap()
+            .get(hash)
+            .and_then(|cache| self.cachix_caches.lock().unwrap().get(cache).cloned());
+        let Some(source) = source else {
+            return self.caches.clone();
+        };
+        match self.config.load().pxe.pin_source_cache {
+            PinSourceCache::First => std::iter::once(source)
+                .chain(self.caches.iter().cloned())
+                .collect(),
+            PinSourceCache::Last => self
+                .caches
+                .iter()
+                .cloned()
+                .chain(std::iter::once(source))
+                .collect(),
+            PinSourceCache::Never => self.caches.clone(),
+        }
+    }
+
+    /// Paths reached through a symbolic link in a pinned path are most likely
+    /// pushed to the same cache.
+    fn inherit_pin_source(&self, from: &str, to: &str) {
+        let mut sources = self.pin_sources.lock().unwrap();
+        if let Some(cache) = sources.get(from).cloned() {
+            sources.entry(to.to_owned()).or_insert(cache);
+        }
+    }
+
+    fn cachix_url(&self, cache: &str) -> Url {
+        let mut base = self.config.load().pxe.cachix_api_base.clone();
+        // Without a trailing slash, `join` would replace the last segment.
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        base.join(&format!("{cache}/")).unwrap()
+    }
+}
+
+/// What a bootloader is told to load. Bootloaders depend on this exact
+/// shape, so fields should only ever be added.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BootResponse {
+    hash: String,
+    store_path: String,
+    cmdline: String,
+    kernel: String,
+    initrd: Vec<String>,
+    files: BTreeMap<String, String>,
 }
 
 #[axum::debug_handler]
 async fn handler_boot_request(
     Path(mac): Path<String>,
     State(state): State<Pxe>,
+) -> Result<Json<BootResponse>, PxeError> {
+    let mac: MacAddr = mac
+        .parse()
+        .map_err(|e: anyhow::Error| PxeError::BadRequest(e.to_string()))?;
+    let config = state.config.load_full();
+    let Some((hostname, _host)) = config.find_host_by_mac(mac) else {
+        return Err(PxeError::UnknownHost(mac));
+    };
+    let _timer = metrics::BOOT_DURATION
+        .with_label_values(&[hostname])
+        .start_timer();
+
+    let pin = cached_pin(&state, hostname).await?;
+    let hash = pin.hash;
+    tracing::info!(hostname, store_path = pin.store_path, "booting");
+    let cmdline = download_file(&state, &hash, "cmdline", Some(hostname)).await?;
+
+    let mut files = BTreeMap::new();
+    for (name, file) in &config.pxe.files {
+        if file_exists(&state, &hash, &file.path, Some(hostname)).await? {
+            files.insert(
+                name.clone(),
+                state.file_url(&hash, &file.path, Some(hostname)),
+            );
+        } else if file.required {
+            return Err(PxeError::MissingFile {
+                name: name.clone(),
+                path: file.path.clone(),
+            });
+        }
+    }
+
+    Ok(Json(BootResponse {
+        cmdline: String::from_utf8(cmdline)?.trim().to_owned(),
+        kernel: state.file_url(&hash, "bzImage", Some(hostname)),
+        initrd: vec![state.file_url(&hash, "initrd", Some(hostname))],
+        store_path: pin.store_path,
+        files,
+        hash,
+    }))
+}
+
+async fn handler_pin(
+    Path(hostname): Path<String>,
+    State(state): State<Pxe>,
 ) -> Result<ErasedJson, PxeError> {
-    let url = Url::parse("https://app.cachix.org/api/v1/cache/")
-        .unwrap()
-        .join(&format!("{}/", &state.config.pxe.cachix))
-        .unwrap();
+    if !state.config.load().host.contains_key(&hostname) {
+        return Err(PxeError::UnknownHostname(hostname));
+    }
 
-    let Some((hostname, _host)) = state.config.find_host_by_mac(&mac) else {
-        return Err(PxeError::UnknownHost(mac));
+    let pin = resolve_pin(&state, &hostname).await?;
+    Ok(json!({
+        "hostname": hostname,
+        "hash": pin.hash,
+        "storePath": pin.store_path,
+        "cache": pin.cache,
+    }))
+}
+
+#[derive(Deserialize)]
+struct PrefetchParams {
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    refresh: bool,
+}
+
+async fn handler_prefetch(
+    Path(hostname): Path<String>,
+    State(state): State<Pxe>,
+    Query(PrefetchParams { refresh }): Query<PrefetchParams>,
+) -> Result<ErasedJson, PxeError> {
+    if state.store.is_none() {
+        return Err(PxeError::BadRequest(
+            "there is no store to prefetch into".to_owned(),
+        ));
+    }
+    if refresh && state.read_only_store {
+        return Err(PxeError::BadRequest(
+            "the store is read-only, so it can't be refreshed".to_owned(),
+        ));
+    }
+    if !state.config.load().host.contains_key(&hostname) {
+        return Err(PxeError::UnknownHostname(hostname));
+    }
+
+    let hash = resolve_pin(&state, &hostname).await?.hash;
+    let options = DownloadOptions {
+        force: refresh,
+        host: Some(&hostname),
+        ..Default::default()
+    };
+    download_path_with(&state, &hash, options).await?;
+
+    Ok(json!({ "hash": hash }))
+}
+
+#[derive(Deserialize)]
+struct BulkPrefetchParams {
+    label: String,
+}
+
+/// How many hosts a bulk prefetch resolves and downloads at once, on top of
+/// `pxe.max_concurrent_downloads`.
+const BULK_PREFETCH_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum PrefetchStatus {
+    /// The pinned path was already in the store.
+    Cached {
+        hash: String,
+    },
+    Downloaded {
+        hash: String,
+    },
+    Error {
+        error: String,
+    },
+}
+
+async fn prefetch_host(state: &PxeState, store: &Store, hostname: &str) -> PrefetchStatus {
+    let result = async {
+        let hash = resolve_pin(state, hostname).await?.hash;
+        let cached = store.lookup(&hash).await?.is_some();
+        download_path(state, &hash, Some(hostname)).await?;
+        anyhow::Ok(match cached {
+            true => PrefetchStatus::Cached { hash },
+            false => PrefetchStatus::Downloaded { hash },
+        })
     };
+    result.await.unwrap_or_else(|e| {
+        tracing::warn!(hostname, "cannot prefetch: {e:#}");
+        PrefetchStatus::Error {
+            error: format!("{e:#}"),
+        }
+    })
+}
+
+/// Download the pinned paths of every host matching `label` into the store,
+/// such as `rack:r1` before a maintenance window.
+async fn handler_bulk_prefetch(
+    State(state): State<Pxe>,
+    Query(BulkPrefetchParams { label }): Query<BulkPrefetchParams>,
+) -> Result<ErasedJson, PxeError> {
+    let Some(store) = &state.store else {
+        return Err(PxeError::BadRequest(
+            "there is no store to prefetch into".to_owned(),
+        ));
+    };
+    let config = state.config.load_full();
+    let hostnames: Vec<String> = config
+        .hosts_with_label(&label)
+        .map(|(h, _)| h.clone())
+        .collect();
+    if hostnames.is_empty() {
+        return Err(PxeError::BadRequest(format!("no host is labelled {label}")));
+    }
+
+    let hosts: BTreeMap<String, PrefetchStatus> = futures::stream::iter(hostnames)
+        .map(|hostname| {
+            let state = &state;
+            async move {
+                let status = prefetch_host(state, store, &hostname).await;
+                (hostname, status)
+            }
+        })
+        .buffer_unordered(BULK_PREFETCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    Ok(json!({ "hosts": hosts }))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum FileStatus {
+    Ok,
+    Missing,
+    Unreadable,
+}
+
+#[derive(Debug, Serialize)]
+struct FileCheck {
+    path: String,
+    required: bool,
+    status: FileStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VerifyResponse {
+    hostname: String,
+    hash: String,
+    store_path: String,
+    /// Whether every required file is there and readable.
+    ok: bool,
+    files: BTreeMap<String, FileCheck>,
+}
+
+async fn check_file(
+    state: &PxeState,
+    hash: &str,
+    path: &str,
+    host: &str,
+) -> (FileStatus, Option<String>) {
+    let unreadable = |e: PxeError| {
+        let message = match e {
+            PxeError::Internal(e) => format!("{e:#}"),
+            PxeError::BadRequest(message) => message,
+            PxeError::NotCached(hash) => format!("{hash} is not cached"),
+            _ => "cannot read file".to_owned(),
+        };
+        (FileStatus::Unreadable, Some(message))
+    };
+
+    if state.store.is_none() {
+        return match file_exists(state, hash, path, Some(host)).await {
+            Ok(true) => (FileStatus::Ok, None),
+            Ok(false) => (FileStatus::Missing, None),
+            Err(e) => unreadable(e),
+        };
+    }
+
+    let opened = match resolve_file(state, hash, path, Some(host)).await {
+        Ok(Some(p)) => tokio::fs::File::open(p).await.map_err(PxeError::from),
+        Ok(None) => return (FileStatus::Missing, None),
+        Err(e) => Err(e),
+    };
+    match opened {
+        Ok(_) => (FileStatus::Ok, None),
+        Err(e) => unreadable(e),
+    }
+}
+
+/// Check that everything a boot of `hostname` needs is present, downloading
+/// the pinned path into the store first if necessary.
+async fn handler_verify(
+    Path(hostname): Path<String>,
+    State(state): State<Pxe>,
+) -> Result<Json<VerifyResponse>, PxeError> {
+    if !state.config.load().host.contains_key(&hostname) {
+        return Err(PxeError::UnknownHostname(hostname));
+    }
+
+    let pin = resolve_pin(&state, &hostname).await?;
+    if state.store.is_some() {
+        download_path(&state, &pin.hash, Some(&hostname)).await?;
+    }
+
+    let boot_files = [
+        ("kernel", "bzImage"),
+        ("initrd", "initrd"),
+        ("cmdline", "cmdline"),
+    ]
+    .map(|(name, path)| (name.to_owned(), path.to_owned(), true));
+    let config = state.config.load_full();
+    let extra_files = config
+        .pxe
+        .files
+        .iter()
+        .map(|(name, file)| (name.clone(), file.path.clone(), file.required));
+
+    let mut ok = true;
+    let mut files = BTreeMap::new();
+    for (name, path, required) in boot_files.into_iter().chain(extra_files) {
+        let (status, error) = check_file(&state, &pin.hash, &path, &hostname).await;
+        if required && !matches!(status, FileStatus::Ok) {
+            ok = false;
+        }
+        files.insert(
+            name,
+            FileCheck {
+                path,
+                required,
+                status,
+                error,
+            },
+        );
+    }
+
+    Ok(Json(VerifyResponse {
+        hostname,
+        hash: pin.hash,
+        store_path: pin.store_path,
+        ok,
+        files,
+    }))
+}
+
+#[derive(Deserialize)]
+struct GcParams {
+    max_bytes: Option<u64>,
+}
 
-    let hash = find_cachix_pin(&state.client, &url, hostname).await?;
-    let cmdline = download_file(&state, &hash, "cmdline").await?;
+async fn handler_gc(
+    State(state): State<Pxe>,
+    Query(GcParams { max_bytes }): Query<GcParams>,
+) -> Result<ErasedJson, PxeError> {
+    let Some(max_bytes) = max_bytes.or(state.config.load().pxe.store_max_bytes) else {
+        return Err(PxeError::BadRequest(
+            "max_bytes must be given when pxe.store_max_bytes is not set".to_owned(),
+        ));
+    };
 
-    Ok(json! ({
-        "cmdline": String::from_utf8(cmdline)?.trim(),
-        "kernel": state.file_url(&hash, "bzImage"),
-        "initrd": [state.file_url(&hash, "initrd")],
+    let Some(store) = &state.store else {
+        return Err(PxeError::BadRequest(
+            "there is no store to collect".to_owned(),
+        ));
+    };
+    if state.read_only_store {
+        return Err(PxeError::BadRequest("the store is read-only".to_owned()));
+    }
+    let stats = store.gc(max_bytes).await?;
+    Ok(json!({
+        "evicted": stats.evicted,
+        "freedBytes": stats.freed_bytes,
     }))
 }
 
 #[derive(Deserialize)]
 struct KeyParam {
+    host: Option<String>,
     key: Option<String>,
 }
 
 enum PxeError {
+    BadRequest(String),
     InvalidAuthentication,
-    UnknownHost(String),
+    RateLimited(Duration),
+    UnknownHost(MacAddr),
+    UnknownHostname(String),
+    MissingFile { name: String, path: String },
+    NoSuchFile { hash: String, path: String },
+    NotCached(String),
     Internal(anyhow::Error),
 }
 
+/// A path which isn't in a read-only store, and so cannot be served.
+#[derive(Debug)]
+struct NotCached(String);
+
+impl std::fmt::Display for NotCached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not in the store", self.0)
+    }
+}
+
+impl std::error::Error for NotCached {}
+
 impl<E> From<E> for PxeError
 where
     E: Into<anyhow::Error>,
 {
     fn from(e: E) -> Self {
-        PxeError::Internal(e.into())
+        let e = e.into();
+        match e.downcast_ref::<NotCached>() {
+            Some(NotCached(hash)) => PxeError::NotCached(hash.clone()),
+            None => PxeError::Internal(e),
+        }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ErrorDetail {
-    error: String,
-}
-
-fn error(message: impl Into<String>) -> impl IntoResponse {
-    return Json(ErrorDetail {
-        error: message.into(),
-    });
-}
-
 impl IntoResponse for PxeError {
     fn into_response(self) -> Response {
         match self {
+            PxeError::BadRequest(message) => {
+                ApiError::new(StatusCode::BAD_REQUEST, message).into_response()
+            }
+
             PxeError::InvalidAuthentication => {
-                (StatusCode::BAD_REQUEST, error("key is missing or invalid")).into_response()
+                ApiError::new(StatusCode::BAD_REQUEST, "key is missing or invalid").into_response()
             }
 
-            PxeError::UnknownHost(mac) => (
-                StatusCode::NOT_FOUND,
-                error(format!("no PXE configuration for MAC {mac}")),
+            PxeError::RateLimited(retry_after) => (
+                [(
+                    http::header::RETRY_AFTER,
+                    retry_after.as_secs().max(1).to_string(),
+                )],
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "too many requests"),
             )
                 .into_response(),
 
-            PxeError::Internal(e) => (
+            PxeError::UnknownHost(mac) => ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("no PXE configuration for MAC {mac}"),
+            )
+            .into_response(),
+
+            PxeError::UnknownHostname(hostname) => {
+                ApiError::new(StatusCode::NOT_FOUND, format!("unknown host {hostname}"))
+                    .into_response()
+            }
+
+            PxeError::MissingFile { name, path } => ApiError::new(
                 StatusCode::INTERNAL_SERVER_ERROR,
+                format!("required boot file {name} ({path}) is missing"),
+            )
+            .into_response(),
+
+            PxeError::NoSuchFile { hash, path } => ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("{hash}/{path} does not exist"),
+            )
+            .into_response(),
+
+            PxeError::NotCached(hash) => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("{hash} is not cached"),
+            )
+            .into_response(),
+
+            PxeError::Internal(e) => (
                 axum::Extension(Arc::new(e)),
-                error("internal server error"),
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal server error"),
             )
                 .into_response(),
         }
     }
 }
 
-async fn handler_file(
-    Path((hash, path)): Path<(String, String)>,
-    State(state): State<Pxe>,
-    Query(KeyParam { key }): Query<KeyParam>,
-) -> Result<Vec<u8>, PxeError> {
+/// Reject file requests which could never be valid before doing any work.
+fn validate_file_request(hash: &str, path: &str) -> Result<(), PxeError> {
+    if !store_path::is_valid_hash(hash) {
+        return Err(PxeError::BadRequest(format!(
+            "invalid store path hash {hash:?}"
+        )));
+    }
+    if !store_path::is_plain_relative(Utf8Path::new(path)) {
+        return Err(PxeError::BadRequest(format!("invalid file path {path:?}")));
+    }
+    Ok(())
+}
+
+/// The content type to serve a boot file as, going by its name.
+fn content_type(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    match name {
+        "cmdline" => "text/plain; charset=utf-8",
+        // Kernels, initrds, and anything we don't know about.
+        _ => "application/octet-stream",
+    }
+}
+
+/// Check that a request for a file is allowed, and is for a signed URL.
+fn authorize_file_request(
+    state: &PxeState,
+    client: SocketAddr,
+    hash: &str,
+    path: &str,
+    host: Option<&str>,
+    key: Option<String>,
+) -> Result<(), PxeError> {
+    if let Some(limiter) = &state.file_rate_limiter {
+        limiter.check(client.ip()).map_err(PxeError::RateLimited)?;
+    }
+
+    validate_file_request(hash, path)?;
+
     let key = key.ok_or(PxeError::InvalidAuthentication)?;
     state
-        .verify_file_url(&hash, &path, &key)
+        .verify_file_url(hash, path, host, &key)
         .map_err(|_| PxeError::InvalidAuthentication)?;
+    Ok(())
+}
+
+async fn handler_file(
+    Path((hash, path)): Path<(String, String)>,
+    State(state): State<Pxe>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(KeyParam { host, key }): Query<KeyParam>,
+) -> Result<impl IntoResponse, PxeError> {
+    authorize_file_request(&state, client, &hash, &path, host.as_deref(), key)?;
+
+    let body = file_body(&state, &hash, &path, host.as_deref()).await?;
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, content_type(&path)),
+            (http::header::ACCEPT_RANGES, "none"),
+        ],
+        body,
+    ))
+}
+
+/// Answer HEAD requests with the size of the file, for clients which check
+/// it before downloading. Without a store, this reads the NAR up to the file.
+async fn handler_file_head(
+    Path((hash, path)): Path<(String, String)>,
+    State(state): State<Pxe>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(KeyParam { host, key }): Query<KeyParam>,
+) -> Result<impl IntoResponse, PxeError> {
+    authorize_file_request(&state, client, &hash, &path, host.as_deref(), key)?;
 
-    let data = download_file(&state, &hash, &path).await?;
-    Ok(data)
+    let Some(size) = file_size(&state, &hash, &path, host.as_deref()).await? else {
+        return Err(PxeError::NoSuchFile { hash, path });
+    };
+    Ok([
+        (http::header::CONTENT_TYPE, content_type(&path).to_owned()),
+        (http::header::CONTENT_LENGTH, size.to_string()),
+        (http::header::ACCEPT_RANGES, "none".to_owned()),
+    ])
 }
 
 use axum::middleware::{Next, from_fn};
@@ -246,33 +1342,922 @@ async fn log_app_errors(request: Request, next: Next) -> Response {
     let response = next.run(request).await;
     // If the response contains an AppError Extension, log it.
     if let Some(err) = response.extensions().get::<Arc<anyhow::Error>>() {
-        tracing::error!(?err, "an unexpected error occurred inside a handler");
+        tracing::error!(
+            ?err,
+            request_id = request_id::current(),
+            "an unexpected error occurred inside a handler"
+        );
     }
     response
 }
 
-pub fn router<S>(config: Config) -> axum::Router<S> {
-    use axum::routing::get;
+/// The store configured in `pxe`, if there is one.
+fn open_store(config: &Config) -> anyhow::Result<Option<Store>> {
+    let Some(dir) = config.pxe.store_dir() else {
+        return Ok(None);
+    };
+    let store = Store::new(dir, config.pxe.optimise_store, config.pxe.store_layout);
+    Ok(Some(match &config.pxe.work_dir {
+        // A read-only store never extracts anything, and mustn't create it.
+        Some(work_dir) if !config.pxe.read_only_store => store.with_work_dir(work_dir)?,
+        _ => store,
+    }))
+}
 
-    let mut secret = [0u8; 32];
-    rand::rng().fill_bytes(&mut secret);
+/// Build the PXE routes. If `require_client_cert` is set, administrative
+/// routes need a client certificate; boot routes never do.
+pub fn router<S>(
+    shared: SharedConfig,
+    require_client_cert: bool,
+) -> anyhow::Result<axum::Router<S>> {
+    use axum::routing::{get, post};
 
-    let state = Pxe::new(PxeState {
-        client: reqwest::Client::new(),
-        caches: config
-            .pxe
-            .caches
-            .iter()
-            .map(|url| BinaryCache::new(url.clone()))
-            .collect(),
-        store: Store::new(&config.pxe.store),
-        config: config.clone(),
-        secret,
-    });
+    let config = shared.load();
+    let store = open_store(&config)?;
+    if let Some(store) = &store
+        && !config.pxe.read_only_store
+    {
+        let moved = store.migrate_layout()?;
+        if moved > 0 {
+            tracing::info!(moved, layout = ?config.pxe.store_layout, "migrated store layout");
+        }
+    }
+    let state = Pxe::new(PxeState::new(shared.clone(), store)?);
+
+    if let Some(interval) = state.pin_refresh {
+        tokio::spawn(refresh_pins(state.clone(), interval));
+    }
+
+    let client_cert_layer =
+        || option_layer(require_client_cert.then(|| from_fn(tls::require_client_certificate)));
 
-    axum::Router::new()
+    Ok(axum::Router::new()
         .route("/v1/boot/{mac}", get(handler_boot_request))
-        .route("/file/{hash}/{*path}", get(handler_file))
+        .route(
+            "/file/{hash}/{*path}",
+            get(handler_file).head(handler_file_head),
+        )
+        .route("/pin/{hostname}", get(handler_pin))
+        .route(
+            "/prefetch",
+            post(handler_bulk_prefetch).route_layer(client_cert_layer()),
+        )
+        .route(
+            "/prefetch/{hostname}",
+            post(handler_prefetch).route_layer(client_cert_layer()),
+        )
+        .route(
+            "/verify/{hostname}",
+            get(handler_verify).route_layer(client_cert_layer()),
+        )
+        .route(
+            "/store/gc",
+            post(handler_gc).route_layer(client_cert_layer()),
+        )
         .layer(from_fn(log_app_errors))
-        .with_state(state)
+        .with_state(state))
+}
+
+/// Exercise the boot flow for `hostname` against a temporary store.
+pub async fn selftest(config: Config, hostname: &str) -> anyhow::Result<()> {
+    if !config.host.contains_key(hostname) {
+        bail!("unknown host {hostname}");
+    }
+
+    let workdir = tempfile::tempdir()?;
+    let state = PxeState::new(
+        Arc::new(ArcSwap::from_pointee(config)),
+        Some(Store::new(workdir.path(), false, StoreLayout::Flat)),
+    )?;
+
+    let hash = resolve_pin(&state, hostname).await?.hash;
+    println!("{hostname} is pinned to {hash}");
+
+    download_path(&state, &hash, Some(hostname)).await?;
+
+    for file in ["bzImage", "initrd", "cmdline"] {
+        let data = download_file(&state, &hash, file, Some(hostname))
+            .await
+            .map_err(|e| match e {
+                PxeError::Internal(e) => e,
+                _ => anyhow!("cannot read {file}"),
+            })?;
+        println!("{file}: {} bytes", data.len());
+
+        let url = state.file_url(&hash, file, Some(hostname));
+        let (_, key) = url
+            .split_once("&key=")
+            .ok_or_else(|| anyhow!("signed URL {url} has no key"))?;
+        state
+            .verify_file_url(&hash, file, Some(hostname), key)
+            .map_err(|e| anyhow!("signed URL for {file} does not verify: {e}"))?;
+        if state
+            .verify_file_url(&hash, "other", Some(hostname), key)
+            .is_ok()
+        {
+            bail!("signed URL for {file} verifies for the wrong path");
+        }
+        if state.verify_file_url(&hash, file, None, key).is_ok() {
+            bail!("signed URL for {file} verifies without its host");
+        }
+    }
+
+    println!("selftest passed");
+    Ok(())
+}
+
+/// Register `path`, an already extracted copy of `hash`, in the store.
+pub async fn import(config: Config, hash: &str, path: &std::path::Path) -> anyhow::Result<()> {
+    let Some(store) = open_store(&config)? else {
+        bail!("there is no store to import into");
+    };
+    let target = store.add_from_dir(hash, path).await?;
+    println!("imported {hash} as {}", target.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_file_request;
+    use super::{
+        BootResponse, BulkPrefetchParams, KeyParam, PrefetchParams, Pxe, PxeState, download_file,
+        find_pin, handler_boot_request, handler_bulk_prefetch, handler_file, handler_file_head,
+        handler_prefetch, handler_verify, resolve_pin,
+    };
+    use crate::binary_cache::Compression;
+    use crate::config::{Config, StoreLayout};
+    use crate::nar;
+    use crate::store::Store;
+    use axum::extract::{ConnectInfo, Path, Query, State};
+    use axum::response::IntoResponse;
+    use http::{HeaderMap, StatusCode, Uri};
+    use sha2::{Digest as _, Sha256};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use url::Url;
+
+    const HASH: &str = "0c0v2bv5yb4wxr9l2kzb0dwbjmgqdxv8";
+
+    /// Serve `files` by path, standing in for both cachix and the binary cache.
+    async fn mock_server(files: HashMap<String, Vec<u8>>) -> Url {
+        mock_server_with(|_| files).await
+    }
+
+    /// Like `mock_server`, for files which need to refer to the server's URL.
+    async fn mock_server_with(files: impl FnOnce(&Url) -> HashMap<String, Vec<u8>>) -> Url {
+        mock_server_validated(None, files).await.0
+    }
+
+    /// Which validator the mock server sends with files, and honours in
+    /// conditional requests.
+    #[derive(Clone, Copy, Debug)]
+    enum Validator {
+        ETag,
+        LastModified,
+    }
+
+    /// Like `mock_server_with`, answering conditional requests with a 304
+    /// if `validator` is given. Also returns how many files were sent in
+    /// full.
+    async fn mock_server_validated(
+        validator: Option<Validator>,
+        files: impl FnOnce(&Url) -> HashMap<String, Vec<u8>>,
+    ) -> (Url, Arc<AtomicUsize>) {
+        use http::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+        // Files never change, so every one of them has the same validator.
+        let (header, condition, value) = match validator {
+            None => (None, None, ""),
+            Some(Validator::ETag) => (Some(ETAG), Some(IF_NONE_MATCH), "\"v1\""),
+            Some(Validator::LastModified) => (
+                Some(LAST_MODIFIED),
+                Some(IF_MODIFIED_SINCE),
+                "Wed, 21 Oct 2015 07:28:00 GMT",
+            ),
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let files = Arc::new(files(&url));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let app = axum::Router::new().fallback(move |uri: Uri, headers: HeaderMap| {
+            let files = files.clone();
+            let counter = counter.clone();
+            async move {
+                let mut response_headers = HeaderMap::new();
+                if let Some(header) = header.clone() {
+                    response_headers.insert(header, value.parse().unwrap());
+                }
+                let Some(data) = files.get(uri.path()) else {
+                    return (StatusCode::NOT_FOUND, response_headers, Vec::new());
+                };
+                if condition.is_some_and(|c| headers.get(c).is_some_and(|v| v == value)) {
+                    return (StatusCode::NOT_MODIFIED, response_headers, Vec::new());
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                (StatusCode::OK, response_headers, data.clone())
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, sent)
+    }
+
+    /// A config with host `node1` and the given `[pxe]` section.
+    fn test_config(pxe: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            [ipmi]
+            username = "admin"
+            password = "hunter2"
+
+            [pxe]
+            {pxe}
+
+            [host.node1]
+            address = "10.0.0.1"
+            mac = "52:54:00:ab:cd:ef"
+            "#
+        ))
+        .unwrap()
+    }
+
+    /// The files of a binary cache at `prefix` with a NAR of `root` as
+    /// `HASH`, and of the Cachix cache `example` pinning host `node1` to it.
+    async fn boot_files(root: &std::path::Path, prefix: &str) -> HashMap<String, Vec<u8>> {
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root).await.unwrap();
+
+        let store_path = format!("/nix/store/{HASH}-nixos-system");
+        let narinfo = format!(
+            "StorePath: {store_path}\nURL: nar/{HASH}.nar\nCompression: none\n\
+             NarHash: sha256:{1:x}\nNarSize: {0}\nFileSize: {0}\n",
+            nar.len(),
+            Sha256::digest(&nar),
+        );
+        let pins = format!(r#"[{{"name":"node1","lastRevision":{{"storePath":"{store_path}"}}}}]"#);
+        HashMap::from([
+            (format!("{prefix}/{HASH}.narinfo"), narinfo.into_bytes()),
+            (format!("{prefix}/nar/{HASH}.nar"), nar),
+            ("/api/example/pin".to_owned(), pins.into_bytes()),
+        ])
+    }
+
+    /// A PXE state whose pin for host `node1` points at a NAR of `root`.
+    async fn boot_state(root: &std::path::Path, store: &std::path::Path) -> Pxe {
+        let url = mock_server(boot_files(root, "").await).await;
+        cachix_state(&url, &url, Some(store))
+    }
+
+    /// A PXE state downloading from `cache` into `store`, with pins from the
+    /// Cachix cache `example` served by `cachix`.
+    /// Without a `store`, files are read from the NAR as it is downloaded.
+    fn cachix_state(cache: &Url, cachix: &Url, store: Option<&std::path::Path>) -> Pxe {
+        let config = test_config(&format!(
+            r#"
+            caches = ["{cache}"]
+            cachix = "example"
+            cachix_api_base = "{cachix}api/"
+            store = "{}"
+            "#,
+            store.map_or("none".into(), |store| store.display().to_string())
+        ));
+        let store = store.map(|_| Store::new(&config.pxe.store, false, StoreLayout::Flat));
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+        Pxe::new(PxeState::new(config, store).unwrap())
+    }
+
+    /// The arguments of a file handler for a request to the signed `url`.
+    fn signed_request(
+        url: &str,
+    ) -> (
+        Path<(String, String)>,
+        ConnectInfo<SocketAddr>,
+        Query<KeyParam>,
+    ) {
+        let url = Url::parse("http://localhost").unwrap().join(url).unwrap();
+        let (hash, path) = url
+            .path()
+            .strip_prefix("/pxe/file/")
+            .and_then(|p| p.split_once('/'))
+            .unwrap();
+        let param = |name| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+        };
+
+        (
+            Path((hash.to_owned(), path.to_owned())),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))),
+            Query(KeyParam {
+                host: param("host"),
+                key: param("key"),
+            }),
+        )
+    }
+
+    async fn fetch_signed(state: &Pxe, url: &str) -> axum::response::Response {
+        let (path, client, query) = signed_request(url);
+        handler_file(path, State(state.clone()), client, query)
+            .await
+            .into_response()
+    }
+
+    async fn head_signed(state: &Pxe, url: &str) -> axum::response::Response {
+        let (path, client, query) = signed_request(url);
+        handler_file_head(path, State(state.clone()), client, query)
+            .await
+            .into_response()
+    }
+
+    async fn body(response: axum::response::Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn boot_and_fetch_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("bzImage"), "kernel").unwrap();
+        std::fs::write(root.path().join("initrd"), "initrd").unwrap();
+        std::fs::write(root.path().join("cmdline"), "init=/init\n").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let state = boot_state(root.path(), store.path()).await;
+
+        let response =
+            handler_boot_request(Path("52:54:00:ab:cd:ef".to_owned()), State(state.clone()))
+                .await
+                .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let boot: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(boot["hash"], HASH);
+        assert_eq!(boot["cmdline"], "init=/init");
+
+        let kernel = fetch_signed(&state, boot["kernel"].as_str().unwrap()).await;
+        assert_eq!(kernel.status(), StatusCode::OK);
+        assert_eq!(
+            kernel.headers()[http::header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        assert_eq!(body(kernel).await, b"kernel");
+
+        let initrd = fetch_signed(&state, boot["initrd"][0].as_str().unwrap()).await;
+        assert_eq!(body(initrd).await, b"initrd");
+
+        let cmdline = fetch_signed(&state, &state.file_url(HASH, "cmdline", None)).await;
+        assert_eq!(
+            cmdline.headers()[http::header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+    }
+
+    #[tokio::test]
+    async fn unchanged_pins_are_not_sent_again() {
+        for validator in [Validator::ETag, Validator::LastModified] {
+            let pins = format!(
+                r#"[{{"name":"node1","lastRevision":{{"storePath":"/nix/store/{HASH}-sys"}}}}]"#
+            );
+            let (url, sent) = mock_server_validated(Some(validator), |_| {
+                HashMap::from([("/api/example/pin".to_owned(), pins.into_bytes())])
+            })
+            .await;
+            let config = test_config(&format!(
+                r#"
+                caches = []
+                cachix = "example"
+                cachix_api_base = "{url}api/"
+                store = "none"
+                pin_source_cache = "never"
+                "#
+            ));
+            let state =
+                PxeState::new(Arc::new(arc_swap::ArcSwap::from_pointee(config)), None).unwrap();
+
+            for _ in 0..3 {
+                let pin = find_pin(&state, "node1", None).await.unwrap().unwrap();
+                assert_eq!(pin.hash, HASH);
+            }
+            assert_eq!(sent.load(Ordering::SeqCst), 1, "{validator:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn head_reports_file_size() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("bzImage"), "kernel").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let url = mock_server(boot_files(root.path(), "").await).await;
+        let stored = cachix_state(&url, &url, Some(store.path()));
+        let storeless = cachix_state(&url, &url, None);
+
+        for state in [stored, storeless] {
+            let response = head_signed(&state, &state.file_url(HASH, "bzImage", None)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[http::header::CONTENT_LENGTH], "6");
+            assert!(body(response).await.is_empty());
+
+            let response = head_signed(&state, &state.file_url(HASH, "initrd", None)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn storeless_files_are_checked_against_nar_hash() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("bzImage"), "kernel").unwrap();
+        std::fs::write(root.path().join("initrd"), "initrd").unwrap();
+        let mut files = boot_files(root.path(), "").await;
+        let url = mock_server(files.clone()).await;
+        let state = cachix_state(&url, &url, None);
+        let data = download_file(&state, HASH, "bzImage", None).await.ok();
+        assert_eq!(data.unwrap(), b"kernel");
+
+        // The file comes before the part of the NAR which was tampered with.
+        let nar = files.get_mut(&format!("/nar/{HASH}.nar")).unwrap();
+        let at = nar.windows(6).position(|w| w == b"initrd").unwrap();
+        nar[at..at + 6].copy_from_slice(b"initrc");
+        let url = mock_server(files).await;
+        let state = cachix_state(&url, &url, None);
+        assert!(download_file(&state, HASH, "bzImage", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_only_store_serves_only_cached_paths() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "init=/init").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let writer = boot_state(root.path(), store.path()).await;
+
+        let mut config = Config::clone(&writer.config.load());
+        config.pxe.read_only_store = true;
+        let replica = Pxe::new(
+            PxeState::new(
+                Arc::new(arc_swap::ArcSwap::from_pointee(config)),
+                Some(Store::new(store.path(), false, StoreLayout::Flat)),
+            )
+            .unwrap(),
+        );
+        let url = replica.file_url(HASH, "cmdline", None);
+
+        let response = fetch_signed(&replica, &url).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(std::fs::read_dir(store.path()).unwrap().count(), 0);
+
+        let response = fetch_signed(&writer, &writer.file_url(HASH, "cmdline", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = fetch_signed(&replica, &url).await;
+        assert_eq!(body(response).await, b"init=/init");
+    }
+
+    #[tokio::test]
+    async fn prefetch_needs_a_writable_store() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "init=/init").unwrap();
+        let url = mock_server(boot_files(root.path(), "").await).await;
+        let store = tempfile::tempdir().unwrap();
+        let writer = cachix_state(&url, &url, Some(store.path()));
+        let mut config = Config::clone(&writer.config.load());
+        config.pxe.read_only_store = true;
+        let replica = Pxe::new(
+            PxeState::new(
+                Arc::new(arc_swap::ArcSwap::from_pointee(config)),
+                Some(Store::new(store.path(), false, StoreLayout::Flat)),
+            )
+            .unwrap(),
+        );
+        let storeless = cachix_state(&url, &url, None);
+        let prefetch = async |state: &Pxe, refresh| {
+            let params = Query(PrefetchParams { refresh });
+            handler_prefetch(Path("node1".to_owned()), State(state.clone()), params)
+                .await
+                .into_response()
+                .status()
+        };
+
+        assert_eq!(prefetch(&storeless, false).await, StatusCode::BAD_REQUEST);
+        assert_eq!(prefetch(&writer, true).await, StatusCode::OK);
+        assert_eq!(prefetch(&replica, false).await, StatusCode::OK);
+        assert_eq!(prefetch(&replica, true).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn download_falls_back_to_pin_source() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "init=/init").unwrap();
+
+        // Only the Cachix cache the pin is in has the path.
+        let mut files = boot_files(root.path(), "/source").await;
+        let url = mock_server_with(|url| {
+            files.insert(
+                "/api/example".to_owned(),
+                format!(r#"{{"uri":"{url}source/"}}"#).into_bytes(),
+            );
+            files
+        })
+        .await;
+        let store = tempfile::tempdir().unwrap();
+        let state = cachix_state(&url.join("mirror/").unwrap(), &url, Some(store.path()));
+
+        let hash = resolve_pin(&state, "node1").await.unwrap().hash;
+        let cmdline = download_file(&state, &hash, "cmdline", None)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(cmdline, b"init=/init");
+    }
+
+    #[tokio::test]
+    async fn pin_revision_selects_cache() {
+        let old = "0000000000000000000000000000000a";
+        let pin = |path: &str, revision: &str| {
+            format!(
+                r#"[{{"name":"node1","lastRevision":{{"storePath":"/nix/store/{path}-nixos-system","revision":{revision}}}}}]"#
+            )
+            .into_bytes()
+        };
+        let url = mock_server(HashMap::from([
+            ("/api/new/pin".to_owned(), pin(HASH, "7")),
+            ("/api/old/pin".to_owned(), pin(old, r#""4f1c2e9""#)),
+        ]))
+        .await;
+
+        let config = test_config(&format!(
+            r#"
+            caches = []
+            cachix = ["new", "old"]
+            cachix_api_base = "{url}api/"
+            pin_source_cache = "never"
+            store = "/nonexistent"
+            fallback = "/nix/store/{HASH}-fallback"
+            "#
+        ));
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+        let state = PxeState::new(config.clone(), None).unwrap();
+        let resolve = async |revision: Option<&str>| {
+            let mut c = (*config.load_full()).clone();
+            c.host.get_mut("node1").unwrap().pin_revision = revision.map(str::to_owned);
+            config.store(Arc::new(c));
+            resolve_pin(&state, "node1").await.map(|pin| pin.hash)
+        };
+
+        assert_eq!(resolve(None).await.unwrap(), HASH);
+        assert_eq!(resolve(Some("7")).await.unwrap(), HASH);
+        assert_eq!(resolve(Some("4f1c2e9")).await.unwrap(), old);
+        // Neither the other caches nor the fallback are booted instead.
+        let error = resolve(Some("deadbeef")).await.unwrap_err();
+        assert!(error.to_string().contains("deadbeef"));
+    }
+
+    #[tokio::test]
+    async fn bulk_prefetch_by_label() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "init=/init").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let state = boot_state(root.path(), store.path()).await;
+
+        let mut config = (*state.config.load_full()).clone();
+        let node1 = config.host.get_mut("node1").unwrap();
+        node1.labels.insert("rack".to_owned(), "r1".to_owned());
+        state.config.store(Arc::new(config));
+        let prefetch = async |label: &str| {
+            let params = BulkPrefetchParams {
+                label: label.to_owned(),
+            };
+            handler_bulk_prefetch(State(state.clone()), Query(params))
+                .await
+                .into_response()
+        };
+
+        let response = prefetch("rack:r1").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(
+            result["hosts"]["node1"],
+            serde_json::json!({ "status": "downloaded", "hash": HASH })
+        );
+
+        let response = prefetch("rack").await;
+        let result: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(result["hosts"]["node1"]["status"], "cached");
+
+        let response = prefetch("rack:r2").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn boot_falls_back_without_pin() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "installer").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let state = boot_state(root.path(), store.path()).await;
+
+        let mut config = (*state.config.load_full()).clone();
+        config.host.insert(
+            "node2".to_owned(),
+            serde_json::from_value(serde_json::json!({
+                "address": "10.0.0.2",
+                "mac": "52:54:00:00:00:02",
+            }))
+            .unwrap(),
+        );
+        state.config.store(Arc::new(config.clone()));
+        let boot =
+            || handler_boot_request(Path("52:54:00:00:00:02".to_owned()), State(state.clone()));
+
+        let response = boot().await.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        config.pxe.fallback = Some(format!("/nix/store/{HASH}-installer"));
+        state.config.store(Arc::new(config));
+        let response = boot().await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let boot: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(boot["hash"], HASH);
+        assert_eq!(boot["cmdline"], "installer");
+    }
+
+    #[test]
+    fn boot_response_shape() {
+        let response = BootResponse {
+            hash: HASH.to_owned(),
+            store_path: format!("/nix/store/{HASH}-nixos-system"),
+            cmdline: "init=/init".to_owned(),
+            kernel: "/pxe/file/bzImage".to_owned(),
+            initrd: vec!["/pxe/file/initrd".to_owned()],
+            files: [("dtb".to_owned(), "/pxe/file/dtb".to_owned())].into(),
+        };
+        let value = serde_json::to_value(&response).unwrap();
+        let mut keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["cmdline", "files", "hash", "initrd", "kernel", "storePath"]
+        );
+        assert_eq!(value["initrd"], serde_json::json!(["/pxe/file/initrd"]));
+        assert_eq!(value["files"]["dtb"], "/pxe/file/dtb");
+    }
+
+    #[tokio::test]
+    async fn verify_reports_missing_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("bzImage"), "kernel").unwrap();
+        std::fs::write(root.path().join("cmdline"), "init=/init").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let state = boot_state(root.path(), store.path()).await;
+
+        let response = handler_verify(Path("node1".to_owned()), State(state))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let verify: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(verify["ok"], false);
+        assert_eq!(verify["files"]["kernel"]["status"], "ok");
+        assert_eq!(verify["files"]["cmdline"]["status"], "ok");
+        assert_eq!(verify["files"]["initrd"]["status"], "missing");
+    }
+
+    #[tokio::test]
+    async fn file_rejects_forged_key() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("bzImage"), "kernel").unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let state = boot_state(root.path(), store.path()).await;
+
+        let url = state.file_url(HASH, "bzImage", None);
+        let forged = url.replace("bzImage", "initrd");
+        assert_eq!(fetch_signed(&state, &url).await.status(), StatusCode::OK);
+        assert_eq!(
+            fetch_signed(&state, &forged).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn boot_unknown_mac() {
+        let root = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let state = boot_state(root.path(), store.path()).await;
+
+        let response = handler_boot_request(Path("52:54:00:00:00:00".to_owned()), State(state))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn file_request_rejects_bad_hashes() {
+        for hash in [&format!("{HASH}0"), "0c0v2bv5yb4wxr9l2kzb0dwbjmgq/xv8"] {
+            let e = validate_file_request(hash, "bzImage").unwrap_err();
+            assert_eq!(e.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn file_request_rejects_traversal() {
+        assert!(validate_file_request(HASH, "boot/bzImage").is_ok());
+        for path in [
+            "../../etc/passwd",
+            "boot/../../x",
+            "/etc/passwd",
+            "./bzImage",
+        ] {
+            let e = validate_file_request(HASH, path).unwrap_err();
+            assert_eq!(e.into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn file_url_depends_only_on_secret() {
+        let store = tempfile::tempdir().unwrap();
+        let a = PxeState::new_for_test(&[], store.path(), [1; 32]);
+        let b = PxeState::new_for_test(&[], store.path(), [1; 32]);
+        let c = PxeState::new_for_test(&[], store.path(), [2; 32]);
+
+        assert_eq!(
+            a.file_url(HASH, "bzImage", None),
+            b.file_url(HASH, "bzImage", None)
+        );
+        assert_ne!(
+            a.file_url(HASH, "bzImage", None),
+            c.file_url(HASH, "bzImage", None)
+        );
+        assert!(
+            b.verify_file_url(
+                HASH,
+                "bzImage",
+                None,
+                &key_of(&a.file_url(HASH, "bzImage", None))
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn file_url_is_bound_to_its_host() {
+        let store = tempfile::tempdir().unwrap();
+        let state = PxeState::new_for_test(&[], store.path(), [1; 32]);
+        let url = state.file_url(HASH, "bzImage", Some("node1"));
+        assert!(url.contains("?host=node1&key="));
+
+        let key = url.split_once("&key=").unwrap().1;
+        assert!(
+            state
+                .verify_file_url(HASH, "bzImage", Some("node1"), key)
+                .is_ok()
+        );
+        assert!(
+            state
+                .verify_file_url(HASH, "bzImage", Some("node2"), key)
+                .is_err()
+        );
+        assert!(state.verify_file_url(HASH, "bzImage", None, key).is_err());
+    }
+
+    #[test]
+    fn secret_file_is_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        std::fs::write(&secret, "hunter2").unwrap();
+        let config = test_config(&format!(
+            r#"
+            caches = []
+            cachix = []
+            store = "none"
+            secret_file = "{}"
+            "#,
+            secret.display()
+        ));
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+        let a = PxeState::new(config.clone(), None).unwrap();
+        let b = PxeState::new(config, None).unwrap();
+
+        assert_eq!(
+            a.file_url(HASH, "bzImage", None),
+            b.file_url(HASH, "bzImage", None)
+        );
+    }
+
+    #[test]
+    fn file_url_keeps_base_path_across_reloads() {
+        let store = tempfile::tempdir().unwrap();
+        let state = PxeState::new_for_test(&[], store.path(), [1; 32]);
+        let mut config = Config::clone(&state.config.load());
+        config.base_path = Some("/elsewhere".to_owned());
+        state.config.store(Arc::new(config));
+
+        assert!(
+            state
+                .file_url(HASH, "bzImage", None)
+                .starts_with("/pxe/file/")
+        );
+    }
+
+    fn key_of(url: &str) -> String {
+        url.split_once("?key=").unwrap().1.to_owned()
+    }
+
+    #[tokio::test]
+    async fn download_file_from_cache() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "quiet").unwrap();
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+        let narinfo = format!(
+            "URL: nar/{HASH}.nar\nCompression: none\nNarSize: {0}\nFileSize: {0}\n",
+            nar.len()
+        );
+        let url = mock_server(HashMap::from([
+            (format!("/{HASH}.narinfo"), narinfo.into_bytes()),
+            (format!("/nar/{HASH}.nar"), nar),
+        ]))
+        .await;
+
+        let store = tempfile::tempdir().unwrap();
+        let state = PxeState::new_for_test(&[url], store.path(), [0; 32]);
+        let data = download_file(&state, HASH, "cmdline", None)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(data, b"quiet");
+        assert!(store.path().join(HASH).join("cmdline").exists());
+    }
+
+    #[tokio::test]
+    async fn download_lz4_nar() {
+        use async_compression::tokio::bufread::Lz4Encoder;
+        use tokio::io::AsyncReadExt as _;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "quiet").unwrap();
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+        let mut compressed = Vec::new();
+        Lz4Encoder::new(nar.as_slice())
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        let narinfo = format!(
+            "URL: nar/{HASH}.nar.lz4\nCompression: lz4\nNarSize: {}\nFileSize: {}\n",
+            nar.len(),
+            compressed.len()
+        );
+        let url = mock_server(HashMap::from([
+            (format!("/{HASH}.narinfo"), narinfo.into_bytes()),
+            (format!("/nar/{HASH}.nar.lz4"), compressed),
+        ]))
+        .await;
+
+        let store = tempfile::tempdir().unwrap();
+        let state = PxeState::new_for_test(&[url], store.path(), [0; 32]);
+        let data = download_file(&state, HASH, "cmdline", None)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(data, b"quiet");
+        assert!(store.path().join(HASH).join("cmdline").exists());
+    }
+
+    #[tokio::test]
+    async fn download_prefers_compression() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("cmdline"), "quiet").unwrap();
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+
+        // The first cache only has an undecodable xz NAR.
+        let xz = mock_server(HashMap::from([
+            (
+                format!("/{HASH}.narinfo"),
+                format!("URL: nar/{HASH}.nar.xz\nCompression: xz\nNarSize: 1\nFileSize: 1\n")
+                    .into_bytes(),
+            ),
+            (format!("/nar/{HASH}.nar.xz"), b"garbage".to_vec()),
+        ]))
+        .await;
+        let plain = mock_server(HashMap::from([
+            (
+                format!("/{HASH}.narinfo"),
+                format!(
+                    "URL: nar/{HASH}.nar\nCompression: none\nNarSize: {0}\nFileSize: {0}\n",
+                    nar.len()
+                )
+                .into_bytes(),
+            ),
+            (format!("/nar/{HASH}.nar"), nar),
+        ]))
+        .await;
+
+        let store = tempfile::tempdir().unwrap();
+        let mut state = PxeState::new_for_test(&[xz, plain], store.path(), [0; 32]);
+        state.compression_preference = vec![Compression::Zstd, Compression::None];
+        let data = download_file(&state, HASH, "cmdline", None)
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(data, b"quiet");
+    }
 }
@@ -0,0 +1,13 @@
+use serde::Deserialize;
+
+/// Accept `1`/`true` and `0`/`false` for boolean query parameters.
+pub fn deserialize_flag<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match String::deserialize(deserializer)?.as_str() {
+        "1" | "true" => Ok(true),
+        "0" | "false" | "" => Ok(false),
+        s => Err(serde::de::Error::custom(format!("invalid flag value: {s}"))),
+    }
+}
@@ -0,0 +1,125 @@
+//! Reloading the configuration when its files change on disk.
+
+use crate::config::{Config, SharedConfig};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long the files must be left alone before they are reloaded, since
+/// editors and config management tools often write them in several steps.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Glob patterns matching every file the configuration at `path` was loaded
+/// from, including the main file itself.
+fn sources(path: &Path, config: &Config) -> Vec<glob::Pattern> {
+    let base = path.parent().unwrap_or(Path::new("."));
+    let main = glob::Pattern::escape(&path.to_string_lossy());
+    std::iter::once(main)
+        .chain(
+            config
+                .include
+                .iter()
+                .map(|p| base.join(p).to_string_lossy().into_owned()),
+        )
+        .filter_map(|p| glob::Pattern::new(&p).ok())
+        .collect()
+}
+
+/// Watch the directories holding `sources`. Files are often replaced by
+/// renaming a new one over them, which a watch on the file itself misses.
+fn watch_dirs(
+    watcher: &mut RecommendedWatcher,
+    watched: &mut BTreeSet<PathBuf>,
+    sources: &[glob::Pattern],
+) -> notify::Result<()> {
+    for pattern in sources {
+        let path = PathBuf::from(pattern.as_str());
+        let Some(dir) = path.parent().map(|d| match d.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => d.to_owned(),
+        }) else {
+            continue;
+        };
+        if watched.contains(&dir) {
+            continue;
+        }
+        if glob::Pattern::escape(&dir.to_string_lossy()) != dir.to_string_lossy() {
+            tracing::warn!(dir = %dir.display(), "cannot watch a directory given as a pattern");
+            continue;
+        }
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        watched.insert(dir);
+    }
+    Ok(())
+}
+
+/// Reload the configuration at `path` into `config` whenever one of its
+/// files changes, applying `prepare` to each new version first. A
+/// configuration which fails to load is logged and otherwise ignored, so the
+/// previous one stays in effect.
+pub fn watch(
+    path: PathBuf,
+    config: SharedConfig,
+    prepare: impl Fn(&mut Config) + Send + 'static,
+) -> anyhow::Result<()> {
+    // Events are reported with absolute paths.
+    let path = std::path::absolute(path)?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+
+    let mut sources = sources(&path, &config.load());
+    let mut watched = BTreeSet::new();
+    watch_dirs(&mut watcher, &mut watched, &sources)?;
+
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            let relevant = match event {
+                Ok(event) => {
+                    !matches!(event.kind, EventKind::Access(_))
+                        && event
+                            .paths
+                            .iter()
+                            .any(|p| sources.iter().any(|s| s.matches_path(p)))
+                }
+                Err(e) => {
+                    tracing::warn!("error watching the configuration: {e}");
+                    false
+                }
+            };
+            if !relevant {
+                continue;
+            }
+
+            loop {
+                match tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => return,
+                    Err(_) => break,
+                }
+            }
+
+            match Config::load(&path) {
+                Ok(mut new) => {
+                    prepare(&mut new);
+                    sources = self::sources(&path, &new);
+                    if let Err(e) = watch_dirs(&mut watcher, &mut watched, &sources) {
+                        tracing::warn!("cannot watch the new configuration's files: {e}");
+                    }
+                    config.store(Arc::new(new));
+                    tracing::info!(path = %path.display(), "reloaded configuration");
+                }
+                Err(e) => {
+                    tracing::error!(
+                        path = %path.display(),
+                        "cannot reload configuration, keeping the previous one: {e:#}"
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use tower_http::request_id::RequestId;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The `x-request-id` of the request currently being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Make the request id set by `SetRequestIdLayer` available through `current`.
+pub async fn scope(request: Request, next: Next) -> Response {
+    let id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_owned);
+
+    match id {
+        Some(id) => REQUEST_ID.scope(id, next.run(request)).await,
+        None => next.run(request).await,
+    }
+}
@@ -1,39 +1,838 @@
+use crate::config::StoreLayout;
+use crate::metrics;
 use crate::nar;
-use anyhow::Context;
-use std::path::PathBuf;
+use crate::store_path;
+use anyhow::{Context, bail};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 use tempfile::tempdir_in;
-use tokio::io::AsyncRead;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::Instrument as _;
+
+type PathLock = Arc<tokio::sync::Mutex<()>>;
 
 pub struct Store {
     path: PathBuf,
+    optimise: bool,
+    layout: StoreLayout,
+    /// Where entries are put together before being moved into place.
+    work_dir: PathBuf,
+    max_nar_entries: u64,
+    locks: Mutex<HashMap<String, PathLock>>,
+}
+
+/// A held path lock, which is given back to the store when dropped however
+/// its holder returns.
+pub struct PathLockGuard<'a> {
+    store: &'a Store,
+    hash: String,
+    lock: Option<PathLock>,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for PathLockGuard<'_> {
+    fn drop(&mut self) {
+        // The guard holds a reference to the lock too, so it goes first.
+        self.guard.take();
+        if let Some(lock) = self.lock.take() {
+            self.store.release_path_lock(&self.hash, lock);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub evicted: usize,
+    pub freed_bytes: u64,
+}
+
+/// Total size of the regular files under `root`. Files that are hard linked
+/// into `.links` are counted in full, so this overestimates what deleting the
+/// entry would free when the store is optimised.
+async fn disk_usage(root: &Path) -> anyhow::Result<u64> {
+    let mut total = 0;
+    let mut pending = vec![root.to_owned()];
+    while let Some(path) = pending.pop() {
+        let metadata = tokio::fs::symlink_metadata(&path).await?;
+        if metadata.is_dir() {
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending.push(entry.path());
+            }
+        } else if metadata.is_file() {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// The shard directory name for `hash` in the sharded layout.
+fn shard(hash: &str) -> &str {
+    hash.get(..2).unwrap_or(hash)
+}
+
+/// Whether `name` is a shard directory rather than an entry. Entry names
+/// always start with a full 32 character hash.
+fn is_shard(name: &std::ffi::OsStr) -> bool {
+    name.len() == 2 && !name.as_bytes().starts_with(b".")
+}
+
+/// Check that `root` is a directory which only holds things a NAR could
+/// contain: directories, regular files and symlinks.
+async fn validate_tree(root: &Path) -> anyhow::Result<()> {
+    let metadata = tokio::fs::symlink_metadata(root)
+        .await
+        .with_context(|| format!("Cannot read {}", root.display()))?;
+    if !metadata.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+    let mut pending = vec![root.to_owned()];
+    while let Some(path) = pending.pop() {
+        let metadata = tokio::fs::symlink_metadata(&path).await?;
+        if metadata.is_dir() {
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending.push(entry.path());
+            }
+        } else if !metadata.is_file() && !metadata.is_symlink() {
+            bail!(
+                "{} is not a regular file, directory or symlink",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Recursively copy `src` to `dst`, keeping symlinks and executable bits.
+async fn copy_tree(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut pending = vec![(src.to_owned(), dst.to_owned())];
+    while let Some((src, dst)) = pending.pop() {
+        let metadata = tokio::fs::symlink_metadata(&src).await?;
+        if metadata.is_dir() {
+            tokio::fs::create_dir(&dst).await?;
+            let mut entries = tokio::fs::read_dir(&src).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending.push((entry.path(), dst.join(entry.file_name())));
+            }
+        } else if metadata.is_symlink() {
+            tokio::fs::symlink(tokio::fs::read_link(&src).await?, &dst).await?;
+        } else {
+            tokio::fs::copy(&src, &dst).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Swap the entries at `a` and `b` in a single step.
+fn exchange(a: &Path, b: &Path) -> std::io::Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings.
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+async fn hash_file(path: &Path, executable: bool) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    // Files that only differ by their executable bit must not share an inode.
+    hasher.update(if executable { b"x" } else { b"-" });
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buf = vec![0u8; 4096 * 8];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
 }
 
 impl Store {
-    pub fn new(path: impl Into<PathBuf>) -> Store {
-        Store { path: path.into() }
+    pub fn new(path: impl Into<PathBuf>, optimise: bool, layout: StoreLayout) -> Store {
+        let path = path.into();
+        Store {
+            work_dir: path.clone(),
+            path,
+            optimise,
+            layout,
+            max_nar_entries: nar::DEFAULT_MAX_ENTRIES,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Extract into `dir` rather than the store directory itself. Entries are
+    /// renamed into the store once complete, so `dir` must be on the same
+    /// filesystem.
+    pub fn with_work_dir(self, dir: impl Into<PathBuf>) -> anyhow::Result<Store> {
+        let work_dir = dir.into();
+        std::fs::create_dir_all(&work_dir)
+            .with_context(|| format!("Cannot create {}", work_dir.display()))?;
+        let store_dev = std::fs::metadata(&self.path)
+            .with_context(|| format!("Cannot access the store at {}", self.path.display()))?
+            .dev();
+        if std::fs::metadata(&work_dir)?.dev() != store_dev {
+            bail!(
+                "the work directory {} is not on the same filesystem as the store at {}",
+                work_dir.display(),
+                self.path.display()
+            );
+        }
+        Ok(Store { work_dir, ..self })
+    }
+
+    /// Refuse to add NARs with more than `max` entries.
+    pub fn with_max_nar_entries(self, max: u64) -> Store {
+        Store {
+            max_nar_entries: max,
+            ..self
+        }
+    }
+
+    /// The directory holding the entry for `hash`.
+    fn parent_dir(&self, hash: &str) -> PathBuf {
+        match self.layout {
+            StoreLayout::Flat => self.path.clone(),
+            StoreLayout::Sharded => self.path.join(shard(hash)),
+        }
+    }
+
+    /// Every directory which may hold entries.
+    async fn parent_dirs(&self) -> anyhow::Result<Vec<PathBuf>> {
+        match self.layout {
+            StoreLayout::Flat => Ok(vec![self.path.clone()]),
+            StoreLayout::Sharded => {
+                let mut dirs = vec![];
+                let mut entries = tokio::fs::read_dir(&self.path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    if is_shard(&entry.file_name()) {
+                        dirs.push(entry.path());
+                    }
+                }
+                Ok(dirs)
+            }
+        }
+    }
+
+    /// Move entries left over from the other layout to where this one
+    /// expects them, returning how many were moved. Anything not named like a
+    /// store path, such as `.links` or a work directory, is left alone. This
+    /// must run before the store is used, since it doesn't take any locks.
+    pub fn migrate_layout(&self) -> anyhow::Result<usize> {
+        let mut moved = 0;
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            match self.layout {
+                StoreLayout::Sharded => {
+                    if is_shard(&name) {
+                        continue;
+                    }
+                    let Some(name) = name.to_str().filter(|n| store_path::is_valid_hash(n)) else {
+                        continue;
+                    };
+                    let parent = self.parent_dir(name);
+                    std::fs::create_dir_all(&parent)?;
+                    std::fs::rename(entry.path(), parent.join(name))?;
+                    moved += 1;
+                }
+                StoreLayout::Flat => {
+                    if !is_shard(&name) {
+                        continue;
+                    }
+                    for child in std::fs::read_dir(entry.path())? {
+                        let child = child?;
+                        let name = child.file_name();
+                        if !name.to_str().is_some_and(store_path::is_valid_hash) {
+                            continue;
+                        }
+                        std::fs::rename(child.path(), self.path.join(name))?;
+                        moved += 1;
+                    }
+                    // Whatever was skipped stays in the shard.
+                    match std::fs::remove_dir(entry.path()) {
+                        Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => {}
+                        result => result?,
+                    }
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Wait for the lock serialising downloads, replacements and evictions
+    /// of `hash`. It is held until the returned guard is dropped.
+    pub async fn lock_path(&self, hash: &str) -> PathLockGuard<'_> {
+        let lock = self.path_lock(hash);
+        let guard = lock.clone().lock_owned().await;
+        PathLockGuard {
+            store: self,
+            hash: hash.to_owned(),
+            lock: Some(lock),
+            guard: Some(guard),
+        }
+    }
+
+    /// Like `lock_path`, but gives up if the lock is already held.
+    fn try_lock_path(&self, hash: &str) -> Option<PathLockGuard<'_>> {
+        let lock = self.path_lock(hash);
+        let mut guard = PathLockGuard {
+            store: self,
+            hash: hash.to_owned(),
+            lock: Some(lock.clone()),
+            guard: None,
+        };
+        guard.guard = Some(lock.try_lock_owned().ok()?);
+        Some(guard)
+    }
+
+    fn path_lock(&self, hash: &str) -> PathLock {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(hash.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// Give back a lock obtained from `path_lock`, forgetting about it if
+    /// nobody else is holding or waiting on it.
+    fn release_path_lock(&self, hash: &str, lock: PathLock) {
+        let mut locks = self.locks.lock().unwrap();
+        // One reference in the map, one being released here.
+        if Arc::strong_count(&lock) == 2 && locks.get(hash).is_some_and(|l| Arc::ptr_eq(l, &lock)) {
+            locks.remove(hash);
+        }
+    }
+
+    /// Evict the oldest entries until the store holds at most `max_bytes`.
+    /// Entries which are currently being written are left alone.
+    pub async fn gc(&self, max_bytes: u64) -> anyhow::Result<GcStats> {
+        let mut entries: Vec<(SystemTime, String, u64, PathBuf)> = vec![];
+        for parent in self.parent_dirs().await? {
+            let mut dir = tokio::fs::read_dir(&parent).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                // Only entries are evictable: not `.links`, shards, work
+                // directories or anything else which ended up in the store,
+                // such as `lost+found`.
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                let hash = name.split_once('-').map_or(name.as_str(), |(hash, _)| hash);
+                if !store_path::is_valid_hash(hash) {
+                    continue;
+                }
+                let modified = entry.metadata().await?.modified()?;
+                let size = disk_usage(&entry.path()).await?;
+                entries.push((modified, name, size, entry.path()));
+            }
+        }
+        entries.sort();
+
+        let mut total: u64 = entries.iter().map(|(_, _, size, _)| size).sum();
+        let mut stats = GcStats::default();
+        for (_, name, size, path) in entries {
+            if total <= max_bytes {
+                break;
+            }
+
+            let hash = name.split_once('-').map_or(name.as_str(), |(hash, _)| hash);
+            if let Some(_guard) = self.try_lock_path(hash) {
+                // Renaming first means readers never see a half-deleted entry.
+                let workdir = tempdir_in(&self.work_dir)?;
+                tokio::fs::rename(&path, workdir.path().join(&name)).await?;
+                tokio::fs::remove_dir_all(workdir.path()).await?;
+
+                total -= size;
+                stats.evicted += 1;
+                stats.freed_bytes += size;
+            }
+        }
+
+        if self.optimise {
+            self.gc_links().await?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Delete files in `.links` which are no longer used by any entry.
+    async fn gc_links(&self) -> anyhow::Result<()> {
+        let mut links = match tokio::fs::read_dir(self.path.join(".links")).await {
+            Ok(links) => links,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(link) = links.next_entry().await? {
+            if link.metadata().await?.nlink() == 1 {
+                tokio::fs::remove_file(link.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace regular files under `root` with hard links into the shared
+    /// `.links` pool, similar to `nix-store --optimise`.
+    async fn optimise(&self, root: &Path, workdir: &Path) -> anyhow::Result<()> {
+        let links = self.path.join(".links");
+        tokio::fs::create_dir_all(&links).await?;
+
+        let mut pending = vec![root.to_owned()];
+        while let Some(path) = pending.pop() {
+            let metadata = tokio::fs::symlink_metadata(&path).await?;
+            if metadata.is_dir() {
+                let mut entries = tokio::fs::read_dir(&path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    pending.push(entry.path());
+                }
+            } else if metadata.is_file() {
+                let executable = metadata.permissions().mode() & 0o111 != 0;
+                let link = links.join(hash_file(&path, executable).await?);
+
+                match tokio::fs::hard_link(&path, &link).await {
+                    Ok(()) => continue,
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                    Err(e) => return Err(e.into()),
+                }
+
+                let tmp = workdir.join(".link");
+                tokio::fs::hard_link(&link, &tmp).await?;
+                tokio::fs::rename(&tmp, &path).await?;
+            }
+        }
+        Ok(())
     }
 
+    /// Find the entry for `hash`, stored either as `<hash>` or `<hash>-<name>`.
     pub async fn lookup(&self, hash: &str) -> anyhow::Result<Option<PathBuf>> {
-        let path = self.path.join(hash);
+        let parent = self.parent_dir(hash);
+        let path = parent.join(hash);
         if path.exists() {
-            Ok(Some(path))
-        } else {
-            Ok(None)
+            return Ok(Some(path));
         }
+
+        let prefix = format!("{hash}-");
+        let mut found: Option<PathBuf> = None;
+        let mut entries = match tokio::fs::read_dir(&parent).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().as_bytes().starts_with(prefix.as_bytes()) {
+                if let Some(previous) = &found {
+                    bail!(
+                        "{hash} is ambiguous, matching both {} and {}",
+                        previous.display(),
+                        entry.path().display()
+                    );
+                }
+                found = Some(entry.path());
+            }
+        }
+        Ok(found)
     }
 
     pub async fn add(&self, hash: &str, data: impl AsyncRead) -> anyhow::Result<PathBuf> {
-        let workdir = tempdir_in(&self.path)?;
+        self.insert(hash, data, false).await
+    }
+
+    /// Like `add`, but replaces the existing entry for `hash` if there is one.
+    pub async fn replace(&self, hash: &str, data: impl AsyncRead) -> anyhow::Result<PathBuf> {
+        self.insert(hash, data, true).await
+    }
+
+    /// Register a directory which was already extracted elsewhere as the
+    /// entry for `hash`. The directory is moved into the store if it lives on
+    /// the same filesystem, and copied otherwise. If adding it fails, a moved
+    /// directory is put back where it was.
+    pub async fn add_from_dir(&self, hash: &str, src: &Path) -> anyhow::Result<PathBuf> {
+        if !store_path::is_valid_hash(hash) {
+            bail!("invalid store path hash {hash:?}");
+        }
+        validate_tree(src).await?;
+
+        let _guard = self.lock_path(hash).await;
+        self.insert_dir(hash, src).await
+    }
+
+    async fn insert_dir(&self, hash: &str, src: &Path) -> anyhow::Result<PathBuf> {
+        if let Some(existing) = self.lookup(hash).await? {
+            bail!("{hash} is already in the store at {}", existing.display());
+        }
+
+        let mut workdir = tempdir_in(&self.work_dir)?;
         let dst = workdir.path().join(hash);
+        let moved = match tokio::fs::rename(src, &dst).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+                copy_tree(src, &dst)
+                    .await
+                    .context("Cannot copy directory into the store")?;
+                false
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let result = async {
+            if self.optimise {
+                self.optimise(&dst, workdir.path())
+                    .await
+                    .context("Cannot optimise store path")?;
+            }
+            let target = self.target(hash).await?;
+            tokio::fs::rename(&dst, &target).await?;
+            Ok(target)
+        }
+        .await;
 
-        nar::Reader::new(data)
+        // The source may be the only copy, so it must not go with the workdir.
+        if result.is_err()
+            && moved
+            && let Err(e) = tokio::fs::rename(&dst, src).await
+        {
+            workdir.disable_cleanup(true);
+            tracing::warn!(?e, src = %src.display(), "cannot move directory back, leaving it at {}", dst.display());
+        }
+        result
+    }
+
+    /// Where the entry for `hash` goes, creating its parent if needed.
+    async fn target(&self, hash: &str) -> anyhow::Result<PathBuf> {
+        let parent = self.parent_dir(hash);
+        tokio::fs::create_dir_all(&parent).await?;
+        Ok(parent.join(hash))
+    }
+
+    async fn insert(
+        &self,
+        hash: &str,
+        data: impl AsyncRead,
+        replace: bool,
+    ) -> anyhow::Result<PathBuf> {
+        let workdir = tempdir_in(&self.work_dir)?;
+        let result = self.insert_in(workdir.path(), hash, data, replace).await;
+        if result.is_err() {
+            // Don't rely on the TempDir's drop for this: it blocks the
+            // runtime and swallows errors, which would leave a half-extracted
+            // tree in the store without a trace.
+            if let Err(e) = tokio::fs::remove_dir_all(workdir.path()).await {
+                tracing::warn!(?e, hash, workdir = %workdir.path().display(), "cannot remove partial extraction");
+            }
+        }
+        result
+    }
+
+    async fn insert_in(
+        &self,
+        workdir: &Path,
+        hash: &str,
+        data: impl AsyncRead,
+        replace: bool,
+    ) -> anyhow::Result<PathBuf> {
+        let dst = workdir.join(hash);
+
+        let started = Instant::now();
+        let stats = nar::Reader::new(data)
+            .with_max_entries(self.max_nar_entries)
             .extract(&dst)
+            .instrument(tracing::info_span!("extract", hash))
             .await
             .context("Cannot extract NAR")?;
+        let elapsed = started.elapsed();
+        tracing::info!(
+            hash,
+            files = stats.files,
+            directories = stats.directories,
+            symlinks = stats.symlinks,
+            bytes = stats.bytes,
+            elapsed_ms = elapsed.as_millis() as u64,
+            input_wait_ms = stats.input_wait.as_millis() as u64,
+            "extracted NAR"
+        );
+        metrics::NAR_EXTRACT_DURATION.observe(elapsed.as_secs_f64());
+        for (kind, count) in [
+            ("file", stats.files),
+            ("directory", stats.directories),
+            ("symlink", stats.symlinks),
+        ] {
+            metrics::NAR_EXTRACTED_ENTRIES
+                .with_label_values(&[kind])
+                .inc_by(count);
+        }
+        metrics::NAR_EXTRACTED_BYTES.inc_by(stats.bytes);
+        metrics::NAR_EXTRACT_INPUT_WAIT.inc_by(stats.input_wait.as_secs_f64());
 
-        let target = self.path.join(hash);
-        tokio::fs::rename(&dst, &target).await?;
+        if self.optimise {
+            self.optimise(&dst, workdir)
+                .await
+                .context("Cannot optimise store path")?;
+        }
+
+        let target = self.target(hash).await?;
+        if replace && tokio::fs::try_exists(&target).await? {
+            // Swap rather than move the old entry out first, so that lookups
+            // never miss in between. It gets deleted along with the workdir.
+            exchange(&dst, &target).context("Cannot replace store path")?;
+        } else {
+            tokio::fs::rename(&dst, &target).await?;
+        }
 
         Ok(target)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Store;
+    use crate::config::StoreLayout;
+    use crate::nar;
+
+    const HASH: &str = "0c0v2bv5yb4wxr9l2kzb0dwbjmgqdxv8";
+
+    #[tokio::test]
+    async fn add_from_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let src = root.path().join("src");
+        std::fs::create_dir_all(src.join("boot")).unwrap();
+        std::fs::write(src.join("boot/bzImage"), "kernel").unwrap();
+        std::os::unix::fs::symlink("boot/bzImage", src.join("kernel")).unwrap();
+
+        let store = tempfile::tempdir().unwrap();
+        let store = Store::new(store.path(), false, StoreLayout::Flat);
+        let path = store.add_from_dir(HASH, &src).await.unwrap();
+        assert_eq!(std::fs::read(path.join("kernel")).unwrap(), b"kernel");
+        assert_eq!(store.lookup(HASH).await.unwrap(), Some(path));
+        assert!(!src.exists());
+    }
+
+    /// Extraction must keep up with the download rather than wait for the
+    /// whole NAR, which can be gigabytes.
+    #[tokio::test]
+    async fn add_extracts_while_streaming() {
+        use tokio::io::AsyncWriteExt as _;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a"), "first").unwrap();
+        std::fs::write(root.path().join("b"), vec![0; 1 << 20]).unwrap();
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path(), false, StoreLayout::Flat);
+        let (mut tx, rx) = tokio::io::duplex(4096);
+        let feed = async {
+            let (head, tail) = nar.split_at(nar.len() / 2);
+            tx.write_all(head).await.unwrap();
+            // `a` shows up in the working directory before the rest is sent.
+            let extracted = || {
+                std::fs::read_dir(dir.path())
+                    .unwrap()
+                    .any(|e| e.unwrap().path().join(HASH).join("a").exists())
+            };
+            while !extracted() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            tx.write_all(tail).await.unwrap();
+            drop(tx);
+        };
+
+        let (path, ()) = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            tokio::join!(store.add(HASH, rx), feed)
+        })
+        .await
+        .expect("extraction waited for the whole NAR");
+        let path = path.unwrap();
+        assert_eq!(std::fs::read(path.join("a")).unwrap(), b"first");
+        assert_eq!(std::fs::metadata(path.join("b")).unwrap().len(), 1 << 20);
+    }
+
+    #[tokio::test]
+    async fn add_through_work_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a"), "a").unwrap();
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path().join("store"), false, StoreLayout::Flat);
+        assert!(store.with_work_dir(dir.path().join("work")).is_err());
+
+        std::fs::create_dir(dir.path().join("store")).unwrap();
+        let store = Store::new(dir.path().join("store"), false, StoreLayout::Flat)
+            .with_work_dir(dir.path().join("work"))
+            .unwrap();
+        let path = store.add(HASH, &nar[..]).await.unwrap();
+        assert_eq!(path, dir.path().join("store").join(HASH));
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("work")).unwrap().count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_swaps_entry() {
+        let pack = async |contents: &str| {
+            let root = tempfile::tempdir().unwrap();
+            std::fs::write(root.path().join("a"), contents).unwrap();
+            let mut nar = Vec::new();
+            nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+            nar
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path(), false, StoreLayout::Flat);
+        store.add(HASH, &pack("old").await[..]).await.unwrap();
+        let path = store.replace(HASH, &pack("new").await[..]).await.unwrap();
+        assert_eq!(std::fs::read(path.join("a")).unwrap(), b"new");
+        // Only the entry is left; the old copy went with the workdir.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn add_from_dir_keeps_source_on_failure() {
+        let root = tempfile::tempdir().unwrap();
+        let src = root.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("file"), "only copy").unwrap();
+
+        // Optimising fails once the source has been moved into the store.
+        let store = tempfile::tempdir().unwrap();
+        std::fs::write(store.path().join(".links"), "").unwrap();
+        let store = Store::new(store.path(), true, StoreLayout::Flat);
+
+        assert!(store.add_from_dir(HASH, &src).await.is_err());
+        assert_eq!(std::fs::read(src.join("file")).unwrap(), b"only copy");
+    }
+
+    #[tokio::test]
+    async fn path_locks_are_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path(), false, StoreLayout::Flat);
+
+        let guard = store.lock_path(HASH).await;
+        assert!(store.try_lock_path(HASH).is_none());
+        assert_eq!(store.locks.lock().unwrap().len(), 1);
+        drop(guard);
+        assert!(store.locks.lock().unwrap().is_empty());
+
+        drop(store.try_lock_path(HASH).unwrap());
+        assert!(store.locks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn migrate_layout() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join(HASH)).unwrap();
+        std::fs::create_dir_all(root.path().join(".links")).unwrap();
+        std::fs::create_dir_all(root.path().join("lost+found")).unwrap();
+
+        let sharded = Store::new(root.path(), false, StoreLayout::Sharded);
+        assert_eq!(sharded.migrate_layout().unwrap(), 1);
+        assert_eq!(sharded.migrate_layout().unwrap(), 0);
+        let path = root.path().join(&HASH[..2]).join(HASH);
+        assert_eq!(sharded.lookup(HASH).await.unwrap(), Some(path));
+        assert!(root.path().join(".links").exists());
+        assert!(root.path().join("lost+found").exists());
+
+        let flat = Store::new(root.path(), false, StoreLayout::Flat);
+        assert_eq!(flat.migrate_layout().unwrap(), 1);
+        let path = root.path().join(HASH);
+        assert_eq!(flat.lookup(HASH).await.unwrap(), Some(path));
+        assert!(!root.path().join(&HASH[..2]).exists());
+    }
+
+    #[tokio::test]
+    async fn gc_only_evicts_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(HASH)).unwrap();
+        std::fs::write(dir.path().join(HASH).join("a"), "a").unwrap();
+        std::fs::create_dir(dir.path().join("lost+found")).unwrap();
+        std::fs::write(dir.path().join("lost+found/#1234"), "b").unwrap();
+        let store = Store::new(dir.path(), false, StoreLayout::Flat)
+            .with_work_dir(dir.path().join("work"))
+            .unwrap();
+
+        let stats = store.gc(0).await.unwrap();
+        assert_eq!(stats.evicted, 1);
+        assert!(!dir.path().join(HASH).exists());
+        assert!(dir.path().join("lost+found/#1234").exists());
+        assert!(dir.path().join("work").exists());
+    }
+
+    #[tokio::test]
+    async fn add_from_dir_rejects_bad_input() {
+        let root = tempfile::tempdir().unwrap();
+        let store = Store::new(root.path(), false, StoreLayout::Flat);
+        let src = tempfile::tempdir().unwrap();
+
+        assert!(store.add_from_dir("not-a-hash", src.path()).await.is_err());
+        std::fs::write(src.path().join("file"), "").unwrap();
+        assert!(
+            store
+                .add_from_dir(HASH, &src.path().join("file"))
+                .await
+                .is_err()
+        );
+
+        store.add_from_dir(HASH, src.path()).await.unwrap();
+        std::fs::create_dir(src.path()).unwrap();
+        assert!(store.add_from_dir(HASH, src.path()).await.is_err());
+        // The failed attempt leaves the source where it was.
+        assert!(src.path().exists());
+    }
+
+    /// A download which fails half-way must not leave anything behind.
+    #[tokio::test]
+    async fn add_cleans_up_after_failure() {
+        use tokio::io::AsyncReadExt as _;
+
+        struct Broken;
+        impl tokio::io::AsyncRead for Broken {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context<'_>,
+                _: &mut tokio::io::ReadBuf<'_>,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+            }
+        }
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a"), vec![0; 1 << 16]).unwrap();
+        let mut nar = Vec::new();
+        nar::Writer::new(&mut nar).pack(root.path()).await.unwrap();
+        nar.truncate(nar.len() / 2);
+        let broken = std::io::Cursor::new(nar).chain(Broken);
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path(), false, StoreLayout::Flat);
+        assert!(store.add(HASH, broken).await.is_err());
+        assert_eq!(store.lookup(HASH).await.unwrap(), None);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use regex::Regex;
+use std::fmt;
+use std::sync::LazyLock;
+
+const STORE_DIR: &str = "/nix/store";
+
+/// Nix's base-32 alphabet omits `e`, `o`, `t` and `u`.
+const HASH_PATTERN: &str = "[0-9a-df-np-sv-z]{32}";
+
+static HASH: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(&format!("^{HASH_PATTERN}$")).expect("regex to be valid"));
+
+static NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(r"^(?<hash>{HASH_PATTERN})-[-.+_?=0-9a-zA-Z]+$"))
+        .expect("regex to be valid")
+});
+
+/// Whether `hash` looks like the hash part of a store path.
+pub fn is_valid_hash(hash: &str) -> bool {
+    HASH.is_match(hash)
+}
+
+/// Whether `path` is relative and only made of plain names, so joining it
+/// onto a directory can't escape that directory.
+pub fn is_plain_relative(path: &Utf8Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Utf8Component::Normal(_)))
+}
+
+/// A path within the Nix store, such as `/nix/store/<hash>-<name>/bin/foo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorePath {
+    pub hash: String,
+    /// The path within the store entry, empty for the entry itself.
+    pub suffix: Utf8PathBuf,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StorePathError {
+    NotInStore(Utf8PathBuf),
+    InvalidName(String),
+    /// The path within the entry isn't a plain relative path, eg. it uses `..`.
+    InvalidSuffix(Utf8PathBuf),
+}
+
+impl fmt::Display for StorePathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorePathError::NotInStore(path) => write!(f, "{path} is not a store path"),
+            StorePathError::InvalidName(name) => write!(f, "invalid store path name {name:?}"),
+            StorePathError::InvalidSuffix(path) => {
+                write!(f, "{path} is not a plain path within a store path")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorePathError {}
+
+impl StorePath {
+    pub fn parse(path: impl AsRef<Utf8Path>) -> Result<StorePath, StorePathError> {
+        let path = path.as_ref();
+        let not_in_store = || StorePathError::NotInStore(path.to_owned());
+
+        let relative = path.strip_prefix(STORE_DIR).map_err(|_| not_in_store())?;
+        let mut components = relative.components();
+        let Some(Utf8Component::Normal(name)) = components.next() else {
+            return Err(not_in_store());
+        };
+
+        let Some(m) = NAME.captures(name) else {
+            return Err(StorePathError::InvalidName(name.to_owned()));
+        };
+
+        let suffix = components.as_path();
+        if !is_plain_relative(suffix) {
+            return Err(StorePathError::InvalidSuffix(suffix.to_owned()));
+        }
+
+        Ok(StorePath {
+            hash: m["hash"].to_owned(),
+            suffix: suffix.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StorePath, StorePathError, is_valid_hash};
+
+    const HASH: &str = "0c0v2bv5yb4wxr9l2kzb0dwbjmgqdxv8";
+
+    #[test]
+    fn parse_entry() {
+        let path = StorePath::parse(format!("/nix/store/{HASH}-hello-2.12")).unwrap();
+        assert_eq!(path.hash, HASH);
+        assert_eq!(path.suffix, "");
+    }
+
+    #[test]
+    fn parse_nested_suffix() {
+        let path = StorePath::parse(format!("/nix/store/{HASH}-linux/boot/bzImage")).unwrap();
+        assert_eq!(path.hash, HASH);
+        assert_eq!(path.suffix, "boot/bzImage");
+    }
+
+    #[test]
+    fn parse_unusual_names() {
+        for name in ["python3.12-foo_bar", "a+b=c?d", "-leading-dash", "x"] {
+            let path = StorePath::parse(format!("/nix/store/{HASH}-{name}")).unwrap();
+            assert_eq!(path.hash, HASH);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_paths_outside_the_store() {
+        for path in [
+            format!("/gnu/store/{HASH}-hello"),
+            format!("/nix/storefoo/{HASH}-hello"),
+            format!("nix/store/{HASH}-hello"),
+            "/nix/store".to_owned(),
+            "/nix/store/".to_owned(),
+        ] {
+            assert!(
+                matches!(StorePath::parse(&path), Err(StorePathError::NotInStore(_))),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_names() {
+        for name in [
+            format!("{}-hello", &HASH[1..]),
+            format!("{HASH}a-hello"),
+            format!("{HASH}-"),
+            HASH.to_owned(),
+            format!("{}e-hello", &HASH[1..]),
+            format!("{HASH}-hello world"),
+        ] {
+            assert!(
+                matches!(
+                    StorePath::parse(format!("/nix/store/{name}")),
+                    Err(StorePathError::InvalidName(_))
+                ),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_rejects_traversal() {
+        assert!(matches!(
+            StorePath::parse(format!("/nix/store/{HASH}-hello/../../../etc/passwd")),
+            Err(StorePathError::InvalidSuffix(_))
+        ));
+    }
+
+    #[test]
+    fn valid_hash() {
+        assert!(is_valid_hash(HASH));
+        assert!(!is_valid_hash(""));
+        assert!(!is_valid_hash(&HASH[1..]));
+        assert!(!is_valid_hash(&format!("{HASH}0")));
+        assert!(!is_valid_hash(&HASH.replace('0', "e")));
+        assert!(!is_valid_hash(&HASH.replace('0', "A")));
+        assert!(!is_valid_hash(&HASH.replace('0', "/")));
+    }
+}
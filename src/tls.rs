@@ -0,0 +1,118 @@
+use crate::config::Tls;
+
+use axum::Extension;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use futures::future::BoxFuture;
+use http::StatusCode;
+use rustls::RootCertStore;
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower_http::add_extension::AddExtension;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// The certificate presented by a client, if it sent one that chains up to
+/// the configured client CA.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub common_name: Option<String>,
+}
+
+impl ClientCertificate {
+    fn from_der(der: &CertificateDer) -> ClientCertificate {
+        let common_name = X509Certificate::from_der(der).ok().and_then(|(_, cert)| {
+            cert.subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_owned)
+        });
+        ClientCertificate { common_name }
+    }
+}
+
+pub async fn server_config(tls: &Tls) -> anyhow::Result<RustlsConfig> {
+    let Some(client_ca) = &tls.client_ca else {
+        return Ok(RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?);
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(client_ca)? {
+        roots.add(cert?)?;
+    }
+
+    // Client certificates are optional at the TLS layer, so that PXE clients
+    // can still connect. Routes that need one use `require_client_certificate`.
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()?;
+
+    let certs = CertificateDer::pem_file_iter(&tls.cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)?;
+
+    let mut config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Wraps a `RustlsAcceptor`, making the client's certificate available to
+/// handlers as an `Option<ClientCertificate>` extension.
+#[derive(Clone)]
+pub struct ClientCertificateAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertificateAcceptor {
+    pub fn new(config: RustlsConfig) -> ClientCertificateAcceptor {
+        ClientCertificateAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertificateAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, Option<ClientCertificate>>;
+    type Future = BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = inner.accept(stream, service).await?;
+            let certificate = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(ClientCertificate::from_der);
+            Ok((stream, AddExtension::new(service, certificate)))
+        })
+    }
+}
+
+pub async fn require_client_certificate(
+    Extension(certificate): Extension<Option<ClientCertificate>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(certificate) = certificate else {
+        return (StatusCode::UNAUTHORIZED, "client certificate required").into_response();
+    };
+    tracing::info!(client = ?certificate.common_name, "authenticated client certificate");
+    next.run(request).await
+}
@@ -9,20 +9,35 @@ import './App.css'
 const fetcher = (url: string) => fetch(url).then(res => res.json());
 
 async function setPowerState(hostname: string, state: boolean) {
-  await fetch(`${import.meta.env.VITE_API_URL || ""}/host/${hostname}/command`, {
+  // Powering off is destructive, so make sure it's the intended host. This
+  // also satisfies the server's `safety.require_confirm` check.
+  if (!state && !window.confirm(`Power off ${hostname}?`)) {
+    return;
+  }
+  await fetch(`${import.meta.env.VITE_API_URL || "."}/host/${hostname}/command`, {
     method: "PUT",
-    body: JSON.stringify({ power: state }),
+    body: JSON.stringify(state ? { power: state } : { power: state, confirm: hostname }),
     headers: {
       'Content-Type': 'application/json'
     },
   });
 }
 
-function SensorRow({ name, value }: {name: string, value: string}) {
+type Sensor = {
+  value: string,
+  lower_critical: string | null,
+  upper_critical: string | null,
+  alarm: boolean,
+};
+
+function SensorRow({ name, sensor }: {name: string, sensor: Sensor}) {
+  const style = sensor.alarm ? { color: "red", fontWeight: "bold" } : {};
   return (
     <TableRow>
-      <TableCell>{name}</TableCell>
-      <TableCell>{value}</TableCell>
+      <TableCell style={style}>{name}</TableCell>
+      <TableCell style={style}>{sensor.value}</TableCell>
+      <TableCell>{sensor.lower_critical ?? ""}</TableCell>
+      <TableCell>{sensor.upper_critical ?? ""}</TableCell>
     </TableRow>);
 }
 
@@ -57,7 +72,7 @@ function HostRow({ hostname, data }: {hostname: string, data: any}) {
             <TableBody>
               { Object.entries(data.sensors)
                     .toSorted(([k1, _v1], [k2, _v2]) => k1.localeCompare(k2))
-                    .map(([k,v]) => <SensorRow name={k} value={v as string} key={k} />) }
+                    .map(([k,v]) => <SensorRow name={k} sensor={v as Sensor} key={k} />) }
             </TableBody>
           </Table>
         </Collapse> }
@@ -67,7 +82,7 @@ function HostRow({ hostname, data }: {hostname: string, data: any}) {
 }
 
 function App() {
-  const { data } = useSWR(`${import.meta.env.VITE_API_URL || ""}/hosts`, fetcher, { refreshInterval: 5000 })
+  const { data } = useSWR(`${import.meta.env.VITE_API_URL || "."}/hosts`, fetcher, { refreshInterval: 5000 })
 
   return (
     <>
@@ -3,6 +3,8 @@ import react from '@vitejs/plugin-react'
 
 // https://vite.dev/config/
 export default defineConfig({
+  // Relative, so the UI works when served under a base path.
+  base: './',
   plugins: [
     react({
       babel: {